@@ -0,0 +1,133 @@
+/// Adaptive dwell: sweep fast with short dwell, then revisit steps with signals with longer dwell
+/// and finer resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSettings {
+    /// Dwell of the coarse sweep, ms
+    pub coarse_dwell_ms: usize,
+    /// Steps whose peak is this much above the noise floor are revisited, dB
+    pub threshold_db: f64,
+    /// Dwell of revisited steps, ms
+    pub dwell_ms: usize,
+    /// Resolution bandwidth of revisited steps, Hz
+    pub rbw: f64,
+}
+
+impl Default for AdaptiveSettings {
+    fn default() -> Self {
+        AdaptiveSettings { coarse_dwell_ms: 4, threshold_db: 10.0, dwell_ms: 64, rbw: 500.0 }
+    }
+}
+
+/// Summary of one step of the coarse sweep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepLevel {
+    /// Frequency of the first bin, Hz
+    pub from: f64,
+    /// End of the last bin, Hz
+    pub to: f64,
+    /// Median power of the step, dB
+    pub median: f64,
+    pub peak: f64,
+}
+
+impl StepLevel {
+    pub fn new(from: f64, bin_width: f64, psd: &[f64]) -> Option<StepLevel> {
+        let median = median(psd)?;
+        let peak = psd.iter().cloned().filter(|p| p.is_finite()).max_by(crate::cmp_f64)?;
+        Some(StepLevel { from, to: from + psd.len() as f64 * bin_width, median, peak })
+    }
+}
+
+/// Median of finite values
+pub fn median(values: &[f64]) -> Option<f64> {
+    let mut values = values.iter().cloned().filter(|v| v.is_finite()).collect::<Vec<_>>();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(crate::cmp_f64);
+    Some(values[values.len() / 2])
+}
+
+/// Noise floor of the sweep, which is the median of steps' medians, so that steps full of
+/// signals do not raise it.
+pub fn noise_floor(steps: &[StepLevel]) -> Option<f64> {
+    median(&steps.iter().map(|s| s.median).collect::<Vec<_>>())
+}
+
+/// Ranges of adjacent steps whose peak exceeds the noise floor by `threshold_db`, Hz
+pub fn hot_regions(steps: &[StepLevel], threshold_db: f64) -> Vec<(u32, u32)> {
+    let floor = match noise_floor(steps) {
+        Some(floor) => floor,
+        None => return vec![],
+    };
+    let mut regions: Vec<(u32, u32)> = vec![];
+    for step in steps.iter().filter(|s| s.peak - floor > threshold_db) {
+        let (from, to) = (step.from.max(0.0) as u32, step.to.ceil() as u32);
+        match regions.last_mut() {
+            Some(last) if last.1 >= from => last.1 = last.1.max(to),
+            _ => regions.push((from, to)),
+        }
+    }
+    regions
+}
+
+/// Signals standing `threshold_db` above the median of `psd` whose first bin is at `freq`:
+/// (lower edge, upper edge, peak power) for every run of adjacent bins above the threshold
+pub fn peaks(freq: f64, bin_width: f64, psd: &[f64], threshold_db: f64) -> Vec<(f64, f64, f64)> {
+    let floor = match median(psd) {
+        Some(floor) => floor,
+        None => return vec![],
+    };
+    let mut peaks: Vec<(f64, f64, f64)> = vec![];
+    let mut in_peak = false;
+    for (k, &p) in psd.iter().enumerate() {
+        let above = p.is_finite() && p - floor > threshold_db;
+        let lower = freq + k as f64 * bin_width;
+        match peaks.last_mut() {
+            Some(last) if above && in_peak => {
+                last.1 = lower + bin_width;
+                last.2 = last.2.max(p);
+            },
+            _ if above => peaks.push((lower, lower + bin_width, p)),
+            _ => {},
+        }
+        in_peak = above;
+    }
+    peaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(from: f64, peak: f64) -> StepLevel {
+        StepLevel { from, to: from + 100.0, median: -80.0, peak }
+    }
+
+    #[test]
+    fn adjacent_hot_steps_are_merged() {
+        let steps = [step(0.0, -75.0), step(100.0, -50.0), step(200.0, -60.0), step(300.0, -79.0), step(400.0, -40.0)];
+        assert_eq!(vec![(100, 300), (400, 500)], hot_regions(&steps, 10.0));
+    }
+
+    #[test]
+    fn quiet_sweep_has_no_regions() {
+        let steps = [step(0.0, -75.0), step(100.0, -78.0)];
+        assert!(hot_regions(&steps, 10.0).is_empty());
+        assert!(hot_regions(&[], 10.0).is_empty());
+    }
+
+    #[test]
+    fn peaks_are_grouped() {
+        let psd = [-80.0, -50.0, -45.0, -80.0, -81.0, -60.0, -79.0];
+        assert_eq!(vec![(10.0, 30.0, -45.0), (50.0, 60.0, -60.0)], peaks(0.0, 10.0, &psd, 10.0));
+    }
+
+    #[test]
+    fn step_level_ignores_missing_bins() {
+        let level = StepLevel::new(1000.0, 10.0, &[-80.0, std::f64::NEG_INFINITY, -70.0, -90.0]).unwrap();
+        assert_eq!(-80.0, level.median);
+        assert_eq!(-70.0, level.peak);
+        assert_eq!(1040.0, level.to);
+    }
+}
@@ -0,0 +1,558 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+//
+// Layout, little endian:
+//   MAGIC, u32 header length, header as json
+//   chunks: CHUNK_MAGIC, u32 rows, rows of (f64 time, u16 level per bin)
+//   index written on finish: INDEX_MAGIC, u32 entries, entries of (f64 time, u64 offset, u32 rows),
+//   u64 offset of the index, INDEX_MAGIC
+// Archive of a writer which did not finish has no index, it is rebuilt by walking the chunks.
+//
+
+pub const EXTENSION: &str = "rtla";
+const MAGIC: &[u8; 8] = b"RTLARCH1";
+const CHUNK_MAGIC: &[u8; 4] = b"CHNK";
+const INDEX_MAGIC: &[u8; 8] = b"RTLAINDX";
+/// Rows are buffered and written a chunk at a time
+const ROWS_PER_CHUNK: usize = 16;
+/// Quantised value of a bin which was not swept
+const MISSING: u16 = std::u16::MAX;
+/// Archive bin width unless configured, Hz
+pub const DEFAULT_BIN_WIDTH: f64 = 10e3;
+
+/// Where a scan archives its sweeps and at which resolution
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveSettings {
+    /// Path and file name prefix, the start time is added to it
+    pub prefix: String,
+    /// Width of archive bins, Hz. Swept bins are max-held into it, so a sweep much finer than
+    /// that does not blow up the archive.
+    pub bin_width: f64,
+}
+
+/// Describes every row of the archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    /// Frequency of the first bin, Hz
+    pub from: f64,
+    /// Width of archive bins, Hz. Sweep's own resolution is `samplerate` and `rbw`.
+    pub bin_width: f64,
+    pub bins: usize,
+    pub samplerate: usize,
+    pub dwell_ms: usize,
+    /// Resolution bandwidth, None when the whole dwell is one FFT frame
+    pub rbw: Option<f64>,
+    /// Quantisation: level is `floor_db + step_db * value`
+    pub floor_db: f64,
+    pub step_db: f64,
+    pub devices: Vec<DeviceCalibration>,
+    /// Seconds since Unix epoch
+    pub start_time: f64,
+}
+
+/// Calibration of a device at the time the archive was written
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceCalibration {
+    pub serial: String,
+    pub ppm: i32,
+    /// Tuner gain, 10th of dB. None for automatic gain.
+    pub gain: Option<i32>,
+    pub level_offset: f64,
+}
+
+/// One sweep, or one cycle through the ranges of a scan plan
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// Seconds since Unix epoch
+    pub time: f64,
+    /// dB per bin, -inf where the bin was not swept
+    pub levels: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// Time of the first row of the chunk
+    pub time: f64,
+    /// Position of the chunk in the file
+    pub offset: u64,
+    pub rows: u32,
+}
+
+impl Header {
+    pub fn new(from: f64, to: f64, bin_width: f64) -> Header {
+        Header {
+            from,
+            bin_width,
+            bins: ((to - from) / bin_width).ceil().max(1.0) as usize,
+            samplerate: 0,
+            dwell_ms: 0,
+            rbw: None,
+            floor_db: -200.0,
+            step_db: 0.01,
+            devices: vec![],
+            start_time: crate::rules::now(),
+        }
+    }
+
+    pub fn to(&self) -> f64 {
+        self.from + self.bins as f64 * self.bin_width
+    }
+
+    pub fn freq(&self, bin: usize) -> f64 {
+        self.from + bin as f64 * self.bin_width
+    }
+
+    /// Bin containing `freq`
+    pub fn bin(&self, freq: f64) -> Option<usize> {
+        let bin = ((freq - self.from) / self.bin_width).floor();
+        if bin >= 0.0 && (bin as usize) < self.bins { Some(bin as usize) } else { None }
+    }
+
+    /// Empty row to be filled with `place`
+    pub fn row(&self, time: f64) -> Row {
+        Row { time, levels: vec![std::f64::NEG_INFINITY; self.bins] }
+    }
+
+    /// Max-hold `psd` whose first bin is at `freq` into the row. A swept bin goes into every
+    /// archive bin it overlaps, so archive bins narrower than swept ones have no gaps and a swept
+    /// bin crossing an archive bin edge counts in both.
+    pub fn place(&self, row: &mut Row, freq: f64, bin_width: f64, psd: &[f64]) {
+        // Tolerance for edges which meet up to rounding
+        const EPSILON: f64 = 1e-6;
+        for (k, &p) in psd.iter().enumerate().filter(|(_, p)| p.is_finite()) {
+            let start = (freq + k as f64 * bin_width - self.from) / self.bin_width;
+            let end = start + bin_width / self.bin_width;
+            let first = (start + EPSILON).floor().max(0.0) as usize;
+            let last = ((end - EPSILON).ceil().max(0.0) as usize).min(self.bins);
+            for level in row.levels.get_mut(first..last).unwrap_or(&mut []) {
+                if p > *level {
+                    *level = p;
+                }
+            }
+        }
+    }
+
+    fn quantise(&self, level: f64) -> u16 {
+        if !level.is_finite() {
+            return MISSING;
+        }
+        ((level - self.floor_db) / self.step_db).round().max(0.0).min((MISSING - 1) as f64) as u16
+    }
+
+    fn level(&self, value: u16) -> f64 {
+        if value == MISSING {
+            std::f64::NEG_INFINITY
+        } else {
+            self.floor_db + self.step_db * value as f64
+        }
+    }
+
+    fn row_size(&self) -> u64 {
+        8 + 2 * self.bins as u64
+    }
+}
+
+pub struct Writer {
+    file: BufWriter<File>,
+    path: String,
+    header: Header,
+    pending: Vec<Row>,
+    /// Position where the next chunk goes
+    offset: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl Writer {
+    pub fn create(path: &str, header: Header) -> Result<Writer, io::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let json = serde_json::to_vec(&header).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&(json.len() as u32).to_le_bytes())?;
+        file.write_all(&json)?;
+        Ok(Writer { file, path: path.to_string(), header, pending: vec![], offset: (MAGIC.len() + 4 + json.len()) as u64, index: vec![] })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn push(&mut self, row: Row) -> Result<(), io::Error> {
+        self.pending.push(row);
+        if self.pending.len() >= ROWS_PER_CHUNK {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> Result<(), io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(CHUNK_MAGIC)?;
+        self.file.write_all(&(self.pending.len() as u32).to_le_bytes())?;
+        for row in &self.pending {
+            self.file.write_all(&row.time.to_bits().to_le_bytes())?;
+            for bin in 0..self.header.bins {
+                let level = row.levels.get(bin).cloned().unwrap_or(std::f64::NEG_INFINITY);
+                self.file.write_all(&self.header.quantise(level).to_le_bytes())?;
+            }
+        }
+        // Chunk is complete on disk even if the writer never finishes
+        self.file.flush()?;
+        let rows = self.pending.len() as u32;
+        self.index.push(IndexEntry { time: self.pending[0].time, offset: self.offset, rows });
+        self.offset += (CHUNK_MAGIC.len() + 4) as u64 + rows as u64 * self.header.row_size();
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write buffered rows and the index
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.write_chunk()?;
+        self.file.write_all(INDEX_MAGIC)?;
+        self.file.write_all(&(self.index.len() as u32).to_le_bytes())?;
+        for entry in &self.index {
+            self.file.write_all(&entry.time.to_bits().to_le_bytes())?;
+            self.file.write_all(&entry.offset.to_le_bytes())?;
+            self.file.write_all(&entry.rows.to_le_bytes())?;
+        }
+        self.file.write_all(&self.offset.to_le_bytes())?;
+        self.file.write_all(INDEX_MAGIC)?;
+        self.file.flush()
+    }
+}
+
+pub struct Reader {
+    file: BufReader<File>,
+    pub header: Header,
+    pub index: Vec<IndexEntry>,
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Reader, io::Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0_u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not an archive"));
+        }
+        let len = read_u32(&mut file)? as usize;
+        let mut json = vec![0_u8; len];
+        file.read_exact(&mut json)?;
+        let header: Header = serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if header.bins == 0 || header.bin_width <= 0.0 {
+            return Err(invalid("empty row"));
+        }
+        let chunks_start = (MAGIC.len() + 4 + len) as u64;
+        let mut reader = Reader { file, header, index: vec![] };
+        reader.index = match reader.read_index()? {
+            Some(index) => index,
+            None => reader.rebuild_index(chunks_start)?,
+        };
+        Ok(reader)
+    }
+
+    /// Index written by `Writer::finish`, None if there is none
+    fn read_index(&mut self) -> Result<Option<Vec<IndexEntry>>, io::Error> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        if len < 16 {
+            return Ok(None);
+        }
+        self.file.seek(SeekFrom::End(-16))?;
+        let offset = read_u64(&mut self.file)?;
+        let mut magic = [0_u8; 8];
+        self.file.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC || offset >= len {
+            return Ok(None);
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Ok(None);
+        }
+        let count = read_u32(&mut self.file)?;
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let time = read_f64(&mut self.file)?;
+            let offset = read_u64(&mut self.file)?;
+            let rows = read_u32(&mut self.file)?;
+            index.push(IndexEntry { time, offset, rows });
+        }
+        Ok(Some(index))
+    }
+
+    /// Walk chunks of an archive whose writer did not finish, dropping an incomplete last chunk
+    fn rebuild_index(&mut self, start: u64) -> Result<Vec<IndexEntry>, io::Error> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        let mut index = vec![];
+        let mut offset = start;
+        while offset + 8 <= len {
+            self.file.seek(SeekFrom::Start(offset))?;
+            let mut magic = [0_u8; 4];
+            self.file.read_exact(&mut magic)?;
+            if &magic != CHUNK_MAGIC {
+                break;
+            }
+            let rows = read_u32(&mut self.file)?;
+            let end = offset + 8 + rows as u64 * self.header.row_size();
+            if rows == 0 || end > len {
+                break;
+            }
+            let time = read_f64(&mut self.file)?;
+            index.push(IndexEntry { time, offset, rows });
+            offset = end;
+        }
+        Ok(index)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.index.iter().map(|e| e.rows as usize).sum()
+    }
+
+    /// Time of the first and the last chunk
+    pub fn time_span(&self) -> Option<(f64, f64)> {
+        Some((self.index.first()?.time, self.index.last()?.time))
+    }
+
+    /// Chunk containing `time`, the first one if it is earlier than the archive
+    pub fn seek(&self, time: f64) -> usize {
+        match self.index.iter().rposition(|e| e.time <= time) {
+            Some(chunk) => chunk,
+            None => 0,
+        }
+    }
+
+    pub fn read_chunk(&mut self, chunk: usize) -> Result<Vec<Row>, io::Error> {
+        let entry = self.index[chunk];
+        self.file.seek(SeekFrom::Start(entry.offset + 8))?;
+        let mut bytes = vec![0_u8; 2 * self.header.bins];
+        let mut rows = Vec::with_capacity(entry.rows as usize);
+        for _ in 0..entry.rows {
+            let time = read_f64(&mut self.file)?;
+            self.file.read_exact(&mut bytes)?;
+            let levels = bytes.chunks(2).map(|b| self.header.level(u16::from_le_bytes([b[0], b[1]]))).collect();
+            rows.push(Row { time, levels });
+        }
+        Ok(rows)
+    }
+
+    /// Rows with time in [from, to]
+    pub fn read_between(&mut self, from: f64, to: f64) -> Result<Vec<Row>, io::Error> {
+        let mut rows = vec![];
+        for chunk in self.seek(from)..self.index.len() {
+            if self.index[chunk].time > to {
+                break;
+            }
+            rows.extend(self.read_chunk(chunk)?.into_iter().filter(|r| r.time >= from && r.time <= to));
+        }
+        Ok(rows)
+    }
+
+    /// Up to `n` last rows with time not after `until`, oldest first
+    pub fn read_last(&mut self, n: usize, until: f64) -> Result<Vec<Row>, io::Error> {
+        let mut rows: Vec<Row> = vec![];
+        let mut chunk = self.seek(until) + 1;
+        while chunk > 0 && rows.len() < n {
+            chunk -= 1;
+            let mut older = self.read_chunk(chunk)?.into_iter().filter(|r| r.time <= until).collect::<Vec<_>>();
+            older.append(&mut rows);
+            rows = older;
+        }
+        let skip = rows.len().saturating_sub(n);
+        Ok(rows.split_off(skip))
+    }
+
+    pub fn read_all(&mut self) -> Result<Vec<Row>, io::Error> {
+        let mut rows = Vec::with_capacity(self.rows());
+        for chunk in 0..self.index.len() {
+            rows.extend(self.read_chunk(chunk)?);
+        }
+        Ok(rows)
+    }
+}
+
+/// Per bin statistics over rows, accumulated a row at a time so that archives do not have to fit
+/// in memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    /// dB, +inf where nothing was swept
+    pub min: Vec<f64>,
+    /// dB, -inf where nothing was swept
+    pub max: Vec<f64>,
+    /// Linear power sum and number of swept rows, per bin
+    sum: Vec<f64>,
+    count: Vec<usize>,
+}
+
+impl Statistics {
+    pub fn new(bins: usize) -> Statistics {
+        Statistics {
+            min: vec![std::f64::INFINITY; bins],
+            max: vec![std::f64::NEG_INFINITY; bins],
+            sum: vec![0.0; bins],
+            count: vec![0; bins],
+        }
+    }
+
+    pub fn add(&mut self, row: &Row) {
+        for (bin, &level) in row.levels.iter().enumerate().take(self.min.len()).filter(|(_, l)| l.is_finite()) {
+            self.min[bin] = self.min[bin].min(level);
+            self.max[bin] = self.max[bin].max(level);
+            self.sum[bin] += 10_f64.powf(level / 10.0);
+            self.count[bin] += 1;
+        }
+    }
+
+    /// Average of power, not of dB
+    pub fn mean(&self) -> Vec<f64> {
+        self.sum.iter().zip(&self.count).map(|(s, &c)| {
+            if c == 0 { std::f64::NEG_INFINITY } else { 10.0 * (s / c as f64).log10() }
+        }).collect()
+    }
+}
+
+/// Convert archive into CSV with a row per sweep: time, then level of every bin. Header line has
+/// bins' frequencies. Returns the number of rows.
+pub fn to_csv(archive: &str, csv: &str) -> Result<usize, io::Error> {
+    let mut reader = Reader::open(archive)?;
+    let mut out = BufWriter::new(File::create(csv)?);
+    write!(out, "time")?;
+    for bin in 0..reader.header.bins {
+        write!(out, ",{}", reader.header.freq(bin))?;
+    }
+    writeln!(out)?;
+    let mut count = 0;
+    for chunk in 0..reader.index.len() {
+        for row in reader.read_chunk(chunk)? {
+            write!(out, "{:.3}", row.time)?;
+            for level in &row.levels {
+                if level.is_finite() {
+                    write!(out, ",{:.2}", level)?;
+                } else {
+                    write!(out, ",")?;
+                }
+            }
+            writeln!(out)?;
+            count += 1;
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32, io::Error> {
+    let mut b = [0_u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, io::Error> {
+    let mut b = [0_u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_f64(r: &mut impl Read) -> Result<f64, io::Error> {
+    Ok(f64::from_bits(read_u64(r)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    fn write(path: &str, rows: usize, finish: bool) -> Header {
+        let header = Header::new(100e6, 100.004e6, 1000.0);
+        let mut writer = Writer::create(path, header.clone()).unwrap();
+        for i in 0..rows {
+            let mut row = header.row(1000.0 + i as f64);
+            header.place(&mut row, 100e6, 1000.0, &[-80.0, -42.3, -70.0 - i as f64]);
+            writer.push(row).unwrap();
+        }
+        if finish {
+            writer.finish().unwrap();
+        }
+        header
+    }
+
+    #[test]
+    fn roundtrip_with_index() {
+        let path = temp("rtl-scanner-archive.rtla");
+        let header = write(&path, 40, true);
+        let mut reader = Reader::open(&path).unwrap();
+        assert_eq!(header, reader.header);
+        assert_eq!(3, reader.index.len());
+        assert_eq!(40, reader.rows());
+        let rows = reader.read_between(1017.0, 1020.0).unwrap();
+        assert_eq!(vec![1017.0, 1018.0, 1019.0, 1020.0], rows.iter().map(|r| r.time).collect::<Vec<_>>());
+        assert_eq!(4, rows[0].levels.len());
+        let last = reader.read_last(20, 1030.5).unwrap();
+        assert_eq!((1011.0, 1030.0), (last[0].time, last[19].time));
+        assert!((rows[0].levels[1] + 42.3).abs() < 0.006);
+        assert_eq!(std::f64::NEG_INFINITY, rows[0].levels[3]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unfinished_archive_is_readable() {
+        let path = temp("rtl-scanner-archive-unfinished.rtla");
+        write(&path, 40, false);
+        let mut reader = Reader::open(&path).unwrap();
+        // The last partial chunk was never written
+        assert_eq!(32, reader.rows());
+        assert_eq!(1016.0, reader.read_chunk(1).unwrap()[0].time);
+        assert_eq!(1, reader.seek(1020.0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn place_max_holds_overlapping_bins() {
+        let header = Header::new(0.0, 4000.0, 1000.0);
+        // Fine sweep: four bins per archive bin
+        let mut row = header.row(0.0);
+        header.place(&mut row, 0.0, 250.0, &[-90.0, -50.0, -80.0, -70.0, -60.0]);
+        assert_eq!(vec![-50.0, -60.0], row.levels[..2].to_vec());
+        assert_eq!(std::f64::NEG_INFINITY, row.levels[2]);
+        // Coarse sweep starting inside a bin covers every bin it overlaps
+        let mut row = header.row(0.0);
+        header.place(&mut row, 500.0, 2000.0, &[-40.0, std::f64::NEG_INFINITY]);
+        assert_eq!(vec![-40.0, -40.0, -40.0], row.levels[..3].to_vec());
+        assert_eq!(std::f64::NEG_INFINITY, row.levels[3]);
+    }
+
+    #[test]
+    fn statistics_and_csv() {
+        let path = temp("rtl-scanner-archive-csv.rtla");
+        let csv = temp("rtl-scanner-archive.csv");
+        write(&path, 2, true);
+        let rows = Reader::open(&path).unwrap().read_all().unwrap();
+        let mut stats = Statistics::new(4);
+        rows.iter().for_each(|row| stats.add(row));
+        assert_eq!((-71.0, -70.0), (stats.min[2], stats.max[2]));
+        let mean = stats.mean();
+        assert!((mean[2] + 70.47).abs() < 0.01);
+        assert_eq!(std::f64::NEG_INFINITY, mean[3]);
+
+        assert_eq!(2, to_csv(&path, &csv).unwrap());
+        let text = fs::read_to_string(&csv).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!("time,100000000,100001000,100002000,100003000", lines[0]);
+        assert_eq!("1001.000,-80.00,-42.30,-71.00,", lines[2]);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(csv).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::thread;
+use crate::fftw::Plan;
+use crate::dsp;
+use crate::rtl_import::rtl_import;
+use crate::correction::{CorrectionSettings, IqCorrector};
+use crate::scanner::{self, ScannerStatus, ScanError, driver_error};
+
+/// ~15Hz resolution at 2Msps, which is 0.15ppm at 100MHz
+const FFT_SIZE: usize = 1 << 17;
+const AVERAGES: usize = 8;
+/// How far from the reference frequency to look for the carrier
+const SEARCH_PPM: f64 = 200.0;
+/// How much the carrier must stand out of the average level of the search window
+const MIN_PEAK_DB: f64 = 10.0;
+
+/// Measure frequency error of the device against a known carrier at `reference` Hz.
+/// Reports `ScannerStatus::Calibrated` with the total correction to be applied to the device.
+pub fn start(serial: String, index: i32, samplerate: usize, reference: u32, ppm: i32) -> Arc<Mutex<VecDeque<ScannerStatus>>> {
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    let queue2 = queue.clone();
+    thread::spawn(move || {
+        queue2.lock().unwrap().push_back(ScannerStatus::Info(
+            format!("Calibrating {} against {} MHz", serial, reference as f64 / 1e6)));
+        let res = measure(&serial, index, samplerate, reference, ppm);
+        let mut queue2 = queue2.lock().unwrap();
+        match res {
+            Ok(residual) => {
+                let corrected = ppm + residual.round() as i32;
+                queue2.push_back(ScannerStatus::Info(format!("Measured error {:.2} ppm", residual)));
+                queue2.push_back(ScannerStatus::Calibrated { serial, ppm: corrected });
+            },
+            Err(err) => queue2.push_back(ScannerStatus::Error(err)),
+        }
+        queue2.push_back(ScannerStatus::Complete);
+    });
+    queue
+}
+
+fn measure(serial: &str, index: i32, samplerate: usize, reference: u32, ppm: i32) -> Result<f64, ScanError> {
+    let mut driver = scanner::open_device(serial, index)?;
+    driver.set_sample_rate(samplerate as u32).map_err(driver_error("set_sample_rate"))?;
+    if ppm != 0 {
+        driver.set_freq_correction(ppm).map_err(driver_error("set_freq_correction"))?;
+    }
+    // Keep the reference away from DC spike
+    let tuned = reference.checked_sub((samplerate / 4) as u32).ok_or_else(|| ScanError::Config(
+        format!("Calibration reference must be above {} MHz", (samplerate / 4) as f64 / 1e6)))?;
+    scanner::tune(&mut driver, serial, tuned)?;
+    driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
+
+    let plan = Plan::new(FFT_SIZE).ok_or(ScanError::FftPlan(FFT_SIZE))?;
+    let input = plan.get_input();
+    let output = plan.get_output();
+    let mut corrector = IqCorrector::new(CorrectionSettings::default());
+
+    // First buffer after retuning may have samples from previous frequency
+    scanner::read(&mut driver, serial, tuned, FFT_SIZE * 2)?;
+
+    let mut average = vec![0_f64; FFT_SIZE];
+    for _ in 0..AVERAGES {
+        let buffer = scanner::read(&mut driver, serial, tuned, FFT_SIZE * 2)?;
+        rtl_import(&buffer, FFT_SIZE * 2, input);
+        corrector.process(input);
+        plan.execute();
+        for (avg, p) in average.iter_mut().zip(dsp::ordered_psd(output)) {
+            *avg += p / AVERAGES as f64;
+        }
+    }
+
+    residual_ppm(&average, tuned as f64, samplerate as f64, reference as f64).
+        ok_or(ScanError::NoCarrier(reference))
+}
+
+/// Find the carrier in ordered (DC in the middle) `psd` captured at `tuned` and return the error
+/// of the local oscillator in ppm, or None if there is no clear carrier near `reference`.
+pub fn residual_ppm(psd: &[f64], tuned: f64, samplerate: f64, reference: f64) -> Option<f64> {
+    let n = psd.len();
+    let bin_width = samplerate / n as f64;
+    let expected = n as f64 / 2.0 + (reference - tuned) / bin_width;
+    let search = (reference * SEARCH_PPM / 1e6 / bin_width).max(2.0);
+    let from = (expected - search).max(1.0) as usize;
+    let to = ((expected + search) as usize).min(n - 2);
+    if from >= to {
+        return None;
+    }
+
+    let window = &psd[from..=to];
+    let (peak_offset, &peak) = window.iter().enumerate().
+        filter(|(_, p)| p.is_finite()).
+        max_by(|a, b| crate::cmp_f64(a.1, b.1))?;
+    let level = window.iter().filter(|p| p.is_finite()).sum::<f64>() / window.len() as f64;
+    if peak - level < MIN_PEAK_DB {
+        return None;
+    }
+
+    // Parabolic interpolation between neighbour bins
+    let k = from + peak_offset;
+    let (a, b, c) = (psd[k-1], peak, psd[k+1]);
+    let denominator = a - 2.0 * b + c;
+    let delta = if denominator.is_finite() && denominator.abs() > std::f64::EPSILON { 0.5 * (a - c) / denominator } else { 0.0 };
+
+    let measured = tuned + (k as f64 + delta - n as f64 / 2.0) * bin_width;
+    // Oscillator running fast by e moves the carrier down by tuned*e
+    Some((reference - measured) / tuned * 1e6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn carrier_at(n: usize, bin: usize) -> Vec<f64> {
+        let mut psd = vec![-80.0; n];
+        psd[bin - 1] = -40.0;
+        psd[bin] = -20.0;
+        psd[bin + 1] = -40.0;
+        psd
+    }
+
+    #[test]
+    fn exact_carrier() {
+        // 1kHz bins, carrier exactly where expected
+        let psd = carrier_at(2000, 1500);
+        let ppm = residual_ppm(&psd, 100e6, 2e6, 100.5e6).unwrap();
+        assert!(ppm.abs() < 1e-9);
+    }
+
+    #[test]
+    fn fast_oscillator() {
+        // Carrier appears 2 bins (2kHz) low, i.e. oscillator is 20ppm fast at 100MHz
+        let psd = carrier_at(2000, 1498);
+        let ppm = residual_ppm(&psd, 100e6, 2e6, 100.5e6).unwrap();
+        assert!((ppm - 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_carrier() {
+        let psd = vec![-80.0; 2000];
+        assert_eq!(None, residual_ppm(&psd, 100e6, 2e6, 100.5e6));
+    }
+}
@@ -1,46 +1,367 @@
+use serde::{Serialize, Deserialize};
+use imgui::*;
+use crate::decimate;
+use crate::settings::ChartSettings;
 
-/// Rescale data sampling to screen resolution.
-/// Also convert FFTW's f64  to f32.
-pub fn rescale(width: i32, height: i32, data: &Vec<f64>) -> Vec<f32> {
-    let mut res = Vec::<f32>::with_capacity(width as usize);
-    //let max = data.iter().cloned().fold(0./0., f64::max);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Colormap {
+    Viridis,
+    Turbo,
+    Grayscale,
+}
+
+/// Colors at even steps from 0 to 1, sRGB
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84], [71, 44, 122], [59, 81, 139], [44, 113, 142], [33, 144, 141],
+    [39, 173, 129], [92, 200, 99], [170, 220, 50], [253, 231, 37],
+];
+const TURBO: [[u8; 3]; 11] = [
+    [48, 18, 59], [69, 91, 205], [62, 156, 254], [24, 214, 203], [70, 247, 131], [162, 252, 60],
+    [225, 221, 55], [254, 164, 49], [239, 90, 17], [194, 36, 3], [122, 4, 3],
+];
+
+impl Colormap {
+    pub const ALL: [Colormap; 3] = [Colormap::Viridis, Colormap::Turbo, Colormap::Grayscale];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Colormap::Viridis => "viridis",
+            Colormap::Turbo => "turbo",
+            Colormap::Grayscale => "grayscale",
+        }
+    }
+
+    /// RGBA of `level` dB between `floor` and `ceiling`. Levels without data are black.
+    pub fn color(&self, level: f32, floor: f32, ceiling: f32) -> [u8; 4] {
+        if !level.is_finite() {
+            return [0, 0, 0, 255];
+        }
+        let x = ((level - floor) / (ceiling - floor).max(1.0)).max(0.0).min(1.0);
+        let stops: &[[u8; 3]] = match self {
+            Colormap::Viridis => &VIRIDIS,
+            Colormap::Turbo => &TURBO,
+            Colormap::Grayscale => &[[0, 0, 0], [255, 255, 255]],
+        };
+        let pos = x * (stops.len() - 1) as f32;
+        let i = (pos as usize).min(stops.len() - 2);
+        let t = pos - i as f32;
+        let mix = |c: usize| (stops[i][c] as f32 * (1.0 - t) + stops[i + 1][c] as f32 * t).round() as u8;
+        [mix(0), mix(1), mix(2), 255]
+    }
+}
+
+/// Wheel notch shrinks the visible span to this fraction
+const ZOOM_STEP: f64 = 0.8;
+/// Zooming stops when this many bins are visible
+const MIN_VISIBLE_BINS: f64 = 8.0;
+/// Shorter drag is a click, pixels
+const MIN_DRAG: f32 = 3.0;
+const MIN_HEIGHT: f32 = 80.0;
+/// Height of the handle under the chart which resizes it
+const RESIZE_HANDLE: f32 = 6.0;
+/// Approximate number of grid lines along each axis
+const GRID_LINES: f64 = 8.0;
+const BACKGROUND: (f32, f32, f32, f32) = (0.05, 0.05, 0.08, 1.0);
+const GRID: (f32, f32, f32, f32) = (0.3, 0.3, 0.3, 0.5);
+/// Opacity of min-max spread relative to the trace
+const ENVELOPE_ALPHA: f32 = 0.4;
+
+/// Visible part of the frequency axis, Hz
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub from: f64,
+    pub to: f64,
+}
+
+impl View {
+    pub fn span(&self) -> f64 {
+        self.to - self.from
+    }
+
+    /// Keep the span, but move it inside `limits`
+    pub fn clamp(self, limits: View) -> View {
+        let span = self.span().min(limits.span());
+        let from = self.from.max(limits.from).min(limits.to - span);
+        View { from, to: from + span }
+    }
+
+    /// Scale the span by `factor` keeping `anchor` at the same place on screen
+    pub fn zoom(self, anchor: f64, factor: f64, limits: View, min_span: f64) -> View {
+        let span = (self.span() * factor).max(min_span).min(limits.span());
+        let pos = (anchor - self.from) / self.span();
+        let from = anchor - pos * span;
+        View { from, to: from + span }.clamp(limits)
+    }
+
+    pub fn pan(self, delta: f64, limits: View) -> View {
+        View { from: self.from + delta, to: self.to + delta }.clamp(limits)
+    }
+}
+
+/// Grid step of 1, 2 or 5 times a power of 10 giving about `lines` lines over `span`
+pub fn nice_step(span: f64, lines: f64) -> f64 {
+    let raw = (span / lines).max(std::f64::MIN_POSITIVE);
+    let magnitude = 10_f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter().map(|m| m * magnitude).find(|&s| s >= raw).unwrap_or(10.0 * magnitude)
+}
+
+/// Series of levels sharing the chart's frequency grid
+pub struct Trace<'a> {
+    pub levels: &'a [f64],
+    pub color: (f32, f32, f32, f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interaction {
+    /// Left click at frequency
+    Click(f64),
+    /// Span selected by dragging with the right button
+    Span(f64, f64),
+}
+
+/// Spectrum chart drawn with the draw list. Every pixel shows the min-max spread and the mean of
+/// the bins under it. Wheel zooms, dragging with the left button pans,
+/// dragging with the right button selects a span.
+pub struct Chart {
+    /// None shows all data
+    pub view: Option<View>,
+    pub auto_scale: bool,
+    /// Manual y scale, dB
+    pub y_min: f32,
+    pub y_max: f32,
+    pub height: f32,
+    /// Screen x and view where left button went down
+    press: Option<(f32, View)>,
+    /// Screen x where span selection started
+    selection: Option<f32>,
+}
 
-    let samples_per_pixel = data.len() as f32 / width as f32;
-    let mut max = ::std::f64::MIN;
-    let mut min = ::std::f64::MAX;
-    //let mut max_bucket = ::std::f64::MIN;
+impl Chart {
+    pub fn new(height: f32) -> Chart {
+        Chart { view: None, auto_scale: true, y_min: -120.0, y_max: 0.0, height, press: None, selection: None }
+    }
+
+    pub fn settings(&self) -> ChartSettings {
+        ChartSettings { auto_scale: self.auto_scale, y_min: self.y_min, y_max: self.y_max, height: self.height }
+    }
+
+    pub fn restore(&mut self, settings: &ChartSettings) {
+        self.auto_scale = settings.auto_scale;
+        self.y_min = settings.y_min;
+        self.y_max = settings.y_max.max(settings.y_min + 1.0);
+        self.height = settings.height.max(MIN_HEIGHT);
+    }
+
+    /// Draw traces whose first bin is at `from`. `spans` are highlighted ranges and `lines` are
+    /// vertical lines at frequencies, such as markers.
+    pub fn render(&mut self, ui: &Ui, id: &str, from: f64, bin_width: f64, traces: &[Trace],
+                  spans: &[(f64, f64, (f32, f32, f32, f32))], lines: &[(f64, (f32, f32, f32, f32))]) -> Option<Interaction> {
+        let width = (ui.get_window_size().0 - 15.0).max(1.0);
+        let height = self.height;
+        let origin = ui.get_cursor_screen_pos();
+        let bins = traces.iter().map(|t| t.levels.len()).max().unwrap_or(0);
+        ui.invisible_button(&ImString::new(format!("##{}", id)), (width, height));
+        let hovered = ui.is_item_hovered();
+        if bins == 0 || bin_width <= 0.0 {
+            ui.get_window_draw_list().add_rect(origin, (origin.0 + width, origin.1 + height), BACKGROUND).filled(true).build();
+            return None;
+        }
+
+        let limits = View { from, to: from + bins as f64 * bin_width };
+        let view = self.view.map_or(limits, |v| v.clamp(limits));
+        let (mouse_x, mouse_y) = ui.imgui().mouse_pos();
+        let freq_at = |x: f32| view.from + ((x - origin.0) / width).max(0.0).min(1.0) as f64 * view.span();
+        let mut interaction = None;
+
+        //
+        // Mouse
+        //
+        let wheel = ui.imgui().mouse_wheel();
+        if hovered && wheel != 0.0 {
+            self.view = Some(view.zoom(freq_at(mouse_x), ZOOM_STEP.powf(wheel as f64), limits, MIN_VISIBLE_BINS * bin_width));
+        }
+        if hovered && ui.imgui().is_mouse_clicked(ImMouseButton::Left) {
+            self.press = Some((mouse_x, view));
+        }
+        if let Some((start, start_view)) = self.press {
+            if ui.imgui().is_mouse_down(ImMouseButton::Left) {
+                if (mouse_x - start).abs() >= MIN_DRAG {
+                    let delta = -((mouse_x - start) / width) as f64 * start_view.span();
+                    self.view = Some(start_view.pan(delta, limits));
+                }
+            } else {
+                self.press = None;
+                if (mouse_x - start).abs() < MIN_DRAG {
+                    interaction = Some(Interaction::Click(freq_at(start)));
+                }
+            }
+        }
+        if hovered && ui.imgui().is_mouse_clicked(ImMouseButton::Right) {
+            self.selection = Some(mouse_x);
+        }
+        if let Some(start) = self.selection {
+            if !ui.imgui().is_mouse_down(ImMouseButton::Right) {
+                self.selection = None;
+                if (mouse_x - start).abs() >= MIN_DRAG {
+                    interaction = Some(Interaction::Span(freq_at(start.min(mouse_x)), freq_at(start.max(mouse_x))));
+                }
+            }
+        }
+        // Pan and zoom above may have moved the view
+        let view = self.view.map_or(limits, |v| v.clamp(limits));
+        let freq_at = |x: f32| view.from + ((x - origin.0) / width).max(0.0).min(1.0) as f64 * view.span();
+        let x_of = |freq: f64| origin.0 + ((freq - view.from) / view.span()) as f32 * width;
+
+        //
+        // Level of detail: visible bins reduced to an envelope per pixel
+        //
+        let pixels = width as usize;
+        let (first, last) = ((view.from - from) / bin_width, (view.to - from) / bin_width);
+        let reduced = traces.iter().map(|t| decimate::envelopes(t.levels, first, last, pixels)).collect::<Vec<_>>();
 
-    let mut running_max = ::std::f64::MIN;
-    let mut end_sample = (1_f32 * samples_per_pixel).round() as usize;
-    for i in 0..data.len() {
-        // TODO: averaging shrinks dynamic range. Maybe do percentiles and adjust color intensity accordingly?
-        //let start_sample = (i as f32 * samples_per_pixel).round() as usize;
-        //let end_sample = ((i+1) as f32 * samples_per_pixel).round() as usize;
-        //let avg: f64 = data.as_slice()[start_sample..end_sample].iter().sum::<f64>() / (end_sample - start_sample) as f64;
+        let range = reduced.iter().filter_map(|r| decimate::range(r)).fold(None, |acc: Option<(f32, f32)>, (min, max)| {
+            Some(acc.map_or((min, max), |(a, b)| (a.min(min), b.max(max))))
+        });
+        let (y_min, y_max) = match range {
+            Some((min, max)) if self.auto_scale => {
+                let margin = ((max - min) * 0.05).max(1.0);
+                (min - margin, max + margin)
+            },
+            _ => (self.y_min, self.y_max.max(self.y_min + 1.0)),
+        };
+        let y_of = |level: f32| origin.1 + height - ((level - y_min) / (y_max - y_min)).max(0.0).min(1.0) * height;
 
-        if running_max < data[i] {
-            running_max = data[i];
+        //
+        // Drawing
+        //
+        let draw_list = ui.get_window_draw_list();
+        draw_list.add_rect(origin, (origin.0 + width, origin.1 + height), BACKGROUND).filled(true).build();
+        let step = nice_step(view.span(), GRID_LINES);
+        let mut f = (view.from / step).ceil() * step;
+        while f < view.to {
+            draw_list.add_line((x_of(f), origin.1), (x_of(f), origin.1 + height), GRID).build();
+            f += step;
+        }
+        let level_step = nice_step((y_max - y_min) as f64, GRID_LINES / 2.0) as f32;
+        let mut level = (y_min / level_step).ceil() * level_step;
+        while level < y_max {
+            draw_list.add_line((origin.0, y_of(level)), (origin.0 + width, y_of(level)), GRID).build();
+            level += level_step;
+        }
+        for (lower, upper, color) in spans {
+            let (x1, x2) = (x_of(*lower).max(origin.0), x_of(*upper).min(origin.0 + width));
+            if x2 >= x1 {
+                draw_list.add_rect((x1, origin.1), (x2.max(x1 + 1.0), origin.1 + height), *color).filled(true).build();
+            }
+        }
+        let x = |pixel: usize| origin.0 + (pixel as f32 + 0.5) * width / pixels as f32;
+        for (trace, envelopes) in traces.iter().zip(&reduced) {
+            // Spread of the bins under each pixel, and a line through their means
+            let (r, g, b, a) = trace.color;
+            for (pixel, e) in envelopes.iter().enumerate().filter(|(_, e)| !e.is_empty() && e.max > e.min) {
+                draw_list.add_line((x(pixel), y_of(e.min)), (x(pixel), y_of(e.max)), (r, g, b, a * ENVELOPE_ALPHA)).build();
+            }
+            for pixel in 1..envelopes.len() {
+                let (previous, current) = (envelopes[pixel - 1], envelopes[pixel]);
+                if !previous.is_empty() && !current.is_empty() {
+                    draw_list.add_line((x(pixel - 1), y_of(previous.mean)), (x(pixel), y_of(current.mean)), trace.color).build();
+                }
+            }
+        }
+        for (freq, color) in lines {
+            let x = x_of(*freq);
+            if x >= origin.0 && x <= origin.0 + width {
+                draw_list.add_line((x, origin.1), (x, origin.1 + height), *color).build();
+            }
+        }
+        if let Some(start) = self.selection {
+            draw_list.add_rect((start.min(mouse_x), origin.1), (start.max(mouse_x), origin.1 + height), (0.3, 0.6, 1.0, 0.3)).
+                filled(true).
+                build();
         }
 
-        if i > end_sample {
-            res.push(running_max as f32);
-            end_sample = (res.len() as f32 * samples_per_pixel).round() as usize;
-            
-            running_max = ::std::f64::MIN;
+        //
+        // Resize handle
+        //
+        ui.invisible_button(&ImString::new(format!("##{}_resize", id)), (width, RESIZE_HANDLE));
+        if ui.is_item_active() {
+            self.height = (self.height + ui.imgui().mouse_delta().1).max(MIN_HEIGHT);
         }
+        let handle_y = origin.1 + height + RESIZE_HANDLE / 2.0;
+        draw_list.add_line((origin.0, handle_y), (origin.0 + width, handle_y), GRID).build();
 
+        //
+        // Readout and scale
+        //
+        if hovered {
+            let freq = freq_at(mouse_x);
+            let level = y_max - (mouse_y - origin.1) / height * (y_max - y_min);
+            let trace = traces[0].levels;
+            let bin = ((freq - from) / bin_width).floor() as usize;
+            let data = trace.get(bin).cloned().unwrap_or(std::f64::NEG_INFINITY);
+            ui.text(im_str!("{:.4} MHz, cursor {:.1} dB, data {:.1} dB", freq / 1e6, level, data));
+        } else {
+            ui.text(im_str!("{:.4}-{:.4} MHz, {:.1}..{:.1} dB", view.from / 1e6, view.to / 1e6, y_min, y_max));
+        }
+        ui.checkbox(&ImString::new(format!("Auto scale##{}", id)), &mut self.auto_scale);
+        if !self.auto_scale {
+            let (mut y_min, mut y_max) = (self.y_min, self.y_max);
+            ui.same_line(0.0);
+            ui.with_item_width(120.0, || {
+                ui.input_float(&ImString::new(format!("Min (dB)##{}", id)), &mut y_min).step(5.0).build();
+                ui.same_line(0.0);
+                ui.input_float(&ImString::new(format!("Max (dB)##{}", id)), &mut y_max).step(5.0).build();
+            });
+            self.y_min = y_min;
+            self.y_max = y_max.max(y_min + 1.0);
+        }
+        if self.view.is_some() {
+            ui.same_line(0.0);
+            if ui.small_button(&ImString::new(format!("Show all##{}", id))) {
+                self.view = None;
+            }
+        }
+        interaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: View = View { from: 0.0, to: 1000.0 };
 
-        //res.push(avg);
-        if data[i] > max
-            {max = data[i];}
-        if data[i] < min && data[i] != std::f64::NEG_INFINITY
-            {min = data[i];}
+    #[test]
+    fn zoom_keeps_anchor() {
+        let view = LIMITS.zoom(250.0, 0.5, LIMITS, 10.0);
+        assert_eq!(View { from: 125.0, to: 625.0 }, view);
+        // Zooming out stops at the limits
+        assert_eq!(LIMITS, view.zoom(250.0, 4.0, LIMITS, 10.0));
+        assert_eq!(10.0, view.zoom(250.0, 0.0001, LIMITS, 10.0).span());
     }
 
-    let amplitude = (max - min) as f32;
-    for i in 0..res.len() {
-        res[i] = (res[i] - min as f32) / amplitude * height as f32
+    #[test]
+    fn pan_stays_inside() {
+        let view = View { from: 100.0, to: 300.0 };
+        assert_eq!(View { from: 0.0, to: 200.0 }, view.pan(-500.0, LIMITS));
+        assert_eq!(View { from: 800.0, to: 1000.0 }, view.pan(5000.0, LIMITS));
+        assert_eq!(View { from: 150.0, to: 350.0 }, view.pan(50.0, LIMITS));
     }
 
-    res
+    #[test]
+    fn grid_steps() {
+        assert_eq!(100.0, nice_step(800.0, 8.0));
+        assert_eq!(200.0, nice_step(1500.0, 8.0));
+        assert_eq!(5e6, nice_step(30e6, 8.0));
+        assert_eq!(10.0, nice_step(80.0, 8.0));
+    }
+
+    #[test]
+    fn colormap_ends() {
+        assert_eq!([68, 1, 84, 255], Colormap::Viridis.color(-120.0, -100.0, -20.0));
+        assert_eq!([253, 231, 37, 255], Colormap::Viridis.color(0.0, -100.0, -20.0));
+        assert_eq!([128, 128, 128, 255], Colormap::Grayscale.color(-60.0, -100.0, -20.0));
+        assert_eq!([0, 0, 0, 255], Colormap::Turbo.color(std::f32::NEG_INFINITY, -100.0, -20.0));
+    }
 }
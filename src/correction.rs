@@ -0,0 +1,207 @@
+// IQ correction stage applied to complex samples before FFT.
+//
+// DC removal:
+// http://www.embedded.com/design/configurable-systems/4007653/DSP-Tricks-DC-Removal
+//
+// IQ imbalance is estimated blindly from the second moments of the signal and compensated by
+// orthogonalizing Q against I and equalizing their power (Gram-Schmidt procedure).
+
+/// Smoothing factor of running estimates, applied once per buffer.
+const TRACKING_ALPHA: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorrectionSettings {
+    pub dc: bool,
+    pub iq_balance: bool,
+    /// Half-width of the interpolated area around DC bin. 0 disables interpolation,
+    /// 1 replaces DC bin only, 2 replaces DC bin and one neighbour on each side and so on.
+    pub center_bins: usize,
+}
+
+impl Default for CorrectionSettings {
+    fn default() -> Self {
+        CorrectionSettings { dc: true, iq_balance: true, center_bins: 1 }
+    }
+}
+
+#[derive(Debug)]
+pub struct IqCorrector {
+    pub settings: CorrectionSettings,
+    dc_re: f64,
+    dc_im: f64,
+    dc_primed: bool,
+    // Running second moments E[I^2], E[Q^2], E[I*Q] of DC-free signal
+    ii: f64,
+    qq: f64,
+    iq: f64,
+    moments_primed: bool,
+}
+
+impl IqCorrector {
+    pub fn new(settings: CorrectionSettings) -> IqCorrector {
+        IqCorrector {
+            settings,
+            dc_re: 0.0,
+            dc_im: 0.0,
+            dc_primed: false,
+            ii: 0.0,
+            qq: 0.0,
+            iq: 0.0,
+            moments_primed: false,
+        }
+    }
+
+    /// DC offset depends on tuned frequency, so start tracking it from scratch after retuning.
+    /// Imbalance estimates are kept because they change slowly.
+    pub fn retuned(&mut self) {
+        self.dc_primed = false;
+    }
+
+    /// Correct interleaved (re,im) samples in place.
+    pub fn process(&mut self, complex: &mut [f64]) {
+        let count = complex.len() / 2;
+        if count == 0 {
+            return;
+        }
+
+        if self.settings.dc {
+            let (mut re_sum, mut im_sum) = (0_f64, 0_f64);
+            for i in (0..count*2).step_by(2) {
+                re_sum += complex[i];
+                im_sum += complex[i+1];
+            }
+            let (re_mean, im_mean) = (re_sum / count as f64, im_sum / count as f64);
+            if self.dc_primed {
+                self.dc_re += TRACKING_ALPHA * (re_mean - self.dc_re);
+                self.dc_im += TRACKING_ALPHA * (im_mean - self.dc_im);
+            } else {
+                self.dc_re = re_mean;
+                self.dc_im = im_mean;
+                self.dc_primed = true;
+            }
+
+            for i in (0..count*2).step_by(2) {
+                complex[i] -= self.dc_re;
+                complex[i+1] -= self.dc_im;
+            }
+        }
+
+        if self.settings.iq_balance {
+            let (mut ii, mut qq, mut iq) = (0_f64, 0_f64, 0_f64);
+            for i in (0..count*2).step_by(2) {
+                let (re, im) = (complex[i], complex[i+1]);
+                ii += re * re;
+                qq += im * im;
+                iq += re * im;
+            }
+            ii /= count as f64;
+            qq /= count as f64;
+            iq /= count as f64;
+            if self.moments_primed {
+                self.ii += TRACKING_ALPHA * (ii - self.ii);
+                self.qq += TRACKING_ALPHA * (qq - self.qq);
+                self.iq += TRACKING_ALPHA * (iq - self.iq);
+            } else {
+                self.ii = ii;
+                self.qq = qq;
+                self.iq = iq;
+                self.moments_primed = true;
+            }
+
+            if let Some((phase, gain)) = self.imbalance() {
+                for i in (0..count*2).step_by(2) {
+                    complex[i+1] = (complex[i+1] - phase * complex[i]) * gain;
+                }
+            }
+        }
+    }
+
+    /// Estimated (phase coupling, gain) correction coefficients: Q' = (Q - phase*I) * gain.
+    /// None if there is not enough signal to estimate.
+    pub fn imbalance(&self) -> Option<(f64, f64)> {
+        if !self.moments_primed || self.ii < std::f64::EPSILON {
+            return None;
+        }
+        let phase = self.iq / self.ii;
+        // E[(Q - phase*I)^2]
+        let qq_orthogonal = self.qq - self.iq * self.iq / self.ii;
+        if qq_orthogonal < std::f64::EPSILON {
+            return None;
+        }
+        Some((phase, (self.ii / qq_orthogonal).sqrt()))
+    }
+}
+
+/// Replace bins around DC of an ordered (DC in the middle) spectrum with linear interpolation
+/// between their neighbours.
+pub fn interpolate_center(psd: &mut [f64], center_bins: usize) {
+    let center = psd.len() / 2;
+    if center_bins == 0 || center < center_bins || center + center_bins >= psd.len() {
+        return;
+    }
+    let left = center - center_bins;
+    let right = center + center_bins;
+    let (from, to) = (psd[left], psd[right]);
+    let span = (right - left) as f64;
+    for i in left+1..right {
+        psd[i] = from + (to - from) * (i - left) as f64 / span;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn tone(count: usize, gain: f64, phase: f64, dc: (f64, f64)) -> Vec<f64> {
+        let mut res = Vec::with_capacity(count * 2);
+        for n in 0..count {
+            let w = 2.0 * PI * n as f64 / 16.0;
+            res.push(w.cos() + dc.0);
+            res.push(gain * (w + phase).sin() + dc.1);
+        }
+        res
+    }
+
+    #[test]
+    fn removes_dc() {
+        let mut data = tone(1024, 1.0, 0.0, (0.3, -0.2));
+        let mut corrector = IqCorrector::new(CorrectionSettings { dc: true, iq_balance: false, center_bins: 0 });
+        corrector.process(&mut data);
+        let re_mean = data.iter().step_by(2).sum::<f64>() / 1024.0;
+        let im_mean = data.iter().skip(1).step_by(2).sum::<f64>() / 1024.0;
+        assert!(re_mean.abs() < 1e-9);
+        assert!(im_mean.abs() < 1e-9);
+    }
+
+    #[test]
+    fn compensates_imbalance() {
+        let mut corrector = IqCorrector::new(CorrectionSettings::default());
+        let mut data = tone(1024, 1.3, 0.2, (0.1, 0.1));
+        corrector.process(&mut data);
+
+        let ii = data.iter().step_by(2).map(|x| x*x).sum::<f64>();
+        let qq = data.iter().skip(1).step_by(2).map(|x| x*x).sum::<f64>();
+        let iq = data.chunks(2).map(|c| c[0]*c[1]).sum::<f64>();
+        assert!((ii - qq).abs() / ii < 1e-6);
+        assert!(iq.abs() / ii < 1e-6);
+    }
+
+    #[test]
+    fn interpolates_center() {
+        let mut psd = vec![1.0, 2.0, 3.0, 100.0, 5.0, 6.0, 7.0];
+        interpolate_center(&mut psd, 1);
+        assert_eq!(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], psd);
+
+        let mut psd = vec![1.0, 2.0, 50.0, 100.0, 50.0, 6.0, 7.0];
+        interpolate_center(&mut psd, 2);
+        assert_eq!(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], psd);
+    }
+
+    #[test]
+    fn interpolation_disabled() {
+        let mut psd = vec![1.0, 100.0, 3.0];
+        interpolate_center(&mut psd, 0);
+        assert_eq!(vec![1.0, 100.0, 3.0], psd);
+    }
+}
@@ -0,0 +1,99 @@
+/// Levels of the bins under one pixel, dB. All are -inf where the pixel has no data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub min: f32,
+    pub max: f32,
+    /// Average of power, not of dB, like `archive::Statistics::mean`. A short strong signal
+    /// raises it more than a dB average would.
+    pub mean: f32,
+}
+
+impl Envelope {
+    pub const EMPTY: Envelope = Envelope {
+        min: std::f32::NEG_INFINITY,
+        max: std::f32::NEG_INFINITY,
+        mean: std::f32::NEG_INFINITY,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        !self.max.is_finite()
+    }
+}
+
+/// Reduce bins `from..to` of `data` to `width` pixels. The window is in bins and may start or
+/// end inside a bin, or reach past the data, which shows as empty pixels. A bin crossed by a
+/// pixel boundary counts in both pixels, so a peak is never lost, and pixels narrower than a bin
+/// repeat it.
+///
+/// Non-finite levels are skipped: -inf comes from `log10(0)` and from bins which were not swept.
+pub fn envelopes<T: Copy + Into<f64>>(data: &[T], from: f64, to: f64, width: usize) -> Vec<Envelope> {
+    let per_pixel = (to - from) / width.max(1) as f64;
+    (0..width).map(|i| {
+        let start = from + i as f64 * per_pixel;
+        let first = start.floor().max(0.0) as usize;
+        let last = ((start + per_pixel).ceil().max(0.0) as usize).max(first + 1).min(data.len());
+        let (mut min, mut max, mut sum, mut n) = (std::f64::INFINITY, std::f64::NEG_INFINITY, 0.0, 0);
+        for level in data.get(first..last).unwrap_or(&[]).iter().map(|&l| l.into()).filter(|l: &f64| l.is_finite()) {
+            min = min.min(level);
+            max = max.max(level);
+            sum += 10_f64.powf(level / 10.0);
+            n += 1;
+        }
+        if n == 0 {
+            Envelope::EMPTY
+        } else {
+            Envelope { min: min as f32, max: max as f32, mean: (10.0 * (sum / n as f64).log10()) as f32 }
+        }
+    }).collect()
+}
+
+/// Envelopes of all of `data`
+pub fn whole<T: Copy + Into<f64>>(data: &[T], width: usize) -> Vec<Envelope> {
+    envelopes(data, 0.0, data.len() as f64, width)
+}
+
+/// Finite range of the envelopes, None if all are empty
+pub fn range(envelopes: &[Envelope]) -> Option<(f32, f32)> {
+    let filled = envelopes.iter().filter(|e| !e.is_empty());
+    let (min, max) = filled.fold((std::f32::INFINITY, std::f32::NEG_INFINITY), |(min, max), e| (min.min(e.min), max.max(e.max)));
+    if max.is_finite() { Some((min, max)) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_all_data() {
+        let data = (0..10).map(|k| k as f64).collect::<Vec<_>>();
+        let pixels = whole(&data, 4);
+        assert_eq!(vec![0.0, 2.0, 5.0, 7.0], pixels.iter().map(|e| e.min).collect::<Vec<_>>());
+        assert_eq!(vec![2.0, 4.0, 7.0, 9.0], pixels.iter().map(|e| e.max).collect::<Vec<_>>());
+        // Power average of 0, 1 and 2 dB
+        assert!((pixels[0].mean - 1.076).abs() < 0.001);
+        assert_eq!(Some((0.0, 9.0)), range(&pixels));
+    }
+
+    #[test]
+    fn missing_levels_are_skipped() {
+        let inf = std::f64::NEG_INFINITY;
+        let pixels = whole(&[-50.0, inf, inf, inf, -70.0, -90.0], 3);
+        assert_eq!(Envelope { min: -50.0, max: -50.0, mean: -50.0 }, pixels[0]);
+        assert!(pixels[1].is_empty());
+        assert_eq!((-90.0, -70.0), (pixels[2].min, pixels[2].max));
+        assert!((pixels[2].mean + 72.97).abs() < 0.01);
+        assert_eq!(None, range(&whole(&[inf, inf], 4)));
+    }
+
+    #[test]
+    fn view_window() {
+        let data = [-10.0_f32, -20.0, -30.0, -40.0];
+        // Zoomed in: a bin spans several pixels
+        let pixels = envelopes(&data, 1.0, 2.0, 4);
+        assert!(pixels.iter().all(|e| e.max == -20.0));
+        // Window starting inside a bin and reaching past the data
+        let pixels = envelopes(&data, 2.5, 5.5, 3);
+        assert_eq!(vec![-30.0, -40.0], pixels[..2].iter().map(|e| e.max).collect::<Vec<_>>());
+        assert!(pixels[2].is_empty());
+    }
+}
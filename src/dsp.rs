@@ -1,5 +1,6 @@
 use std::f64::consts::PI;
 use num::complex::*;
+use crate::iterators::TuplesImpl;
 
 /// https://www.mathworks.com/help/signal/ug/power-spectral-density-estimates-using-fft.html
 pub fn psd(dft: &Vec<Complex64>) -> Vec<f64> {
@@ -12,6 +13,47 @@ pub fn psd(dft: &Vec<Complex64>) -> Vec<f64> {
         collect()
 }
 
+/// Psd of FFTW output, reordered so that negative frequencies come first and DC is in the middle.
+pub fn ordered_psd(output: &[f64]) -> Vec<f64> {
+    // http://www.fftw.org/doc/The-1d-Discrete-Fourier-Transform-_0028DFT_0029.html#The-1d-Discrete-Fourier-Transform-_0028DFT_0029
+    // Note also that we use the standard “in-order” output ordering—the k-th output corresponds to the frequency
+    // k/n (or k/T, where T is your total sampling period). For those who like to think in terms of positive and
+    // negative frequencies, this means that the positive frequencies are stored in the first half of the output
+    // and the negative frequencies are stored in backwards order in the second half of the output.
+    // (The frequency -k/n is the same as the frequency (n-k)/n.)
+    //
+    // Or just numpy implementation:
+    // https://github.com/numpy/numpy/blob/v1.12.0/numpy/fft/helper.py#L74
+    let dft_out_ordered = output[output.len()/2..].iter().chain(output[..output.len()/2].iter()).cloned().tuples();
+    let complex_dft = dft_out_ordered.
+        map(|(re, im)| Complex64::new(re, im)).
+        // TODO: do not collect but keep propagating Iterator into ::psd
+        collect::<Vec<_>>();
+    psd(&complex_dft)
+}
+
+/// Hann window of `n` points
+pub fn hann(n: usize) -> Vec<f64> {
+    (0..n).map(|k| 0.5 - 0.5 * (2.0 * PI * k as f64 / n as f64).cos()).collect()
+}
+
+/// Mean power of `window`, the fraction of signal power left after windowing a frame
+pub fn window_power(window: &[f64]) -> f64 {
+    window.iter().map(|w| w * w).sum::<f64>() / window.len() as f64
+}
+
+/// Add power of `psd` (dB) to linear `sum`, for averaging of several FFT frames.
+pub fn accumulate_power(sum: &mut [f64], psd: &[f64]) {
+    for (s, p) in sum.iter_mut().zip(psd) {
+        *s += 10_f64.powf(p / 10.0);
+    }
+}
+
+/// Average power in dB of `frames` frames accumulated by `accumulate_power`.
+pub fn average_power(sum: &[f64], frames: usize) -> Vec<f64> {
+    sum.iter().map(|s| 10.0 * (s / frames as f64).log10()).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,7 +85,7 @@ mod tests {
     fn compare_to_matlab() {
         let samples = &read_matlab_complex("data/signal.mat");
         println!("samples: {}", samples.len());
-        let fft = Plan::new(samples.len());
+        let fft = Plan::new(samples.len()).unwrap();
         println!("fft: {:?}", fft);
         let input = fft.get_input();
         let output = fft.get_output();
@@ -59,4 +101,20 @@ mod tests {
         let scan = psd(&complex_dft);
         println!("scan: {:?}", scan);
     }
+
+    #[test]
+    fn power_is_averaged_linearly() {
+        let mut sum = vec![0.0; 2];
+        accumulate_power(&mut sum, &[0.0, -10.0]);
+        accumulate_power(&mut sum, &[-10.0, -10.0]);
+        let avg = average_power(&sum, 2);
+        assert!((avg[0] - 10.0 * 0.55_f64.log10()).abs() < 1e-9);
+        assert!((avg[1] + 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn hann_keeps_three_eighths_of_power() {
+        assert!((window_power(&hann(1024)) - 0.375).abs() < 1e-9);
+        assert!((window_power(&[1.0; 16]) - 1.0).abs() < 1e-9);
+    }
 }
\ No newline at end of file
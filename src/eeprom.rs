@@ -0,0 +1,192 @@
+// RTL2832 EEPROM layout, as used by rtl_eeprom from librtlsdr:
+//
+// 0x00    0x28 0x32 signature
+// 0x02    vendor id, little endian
+// 0x04    product id, little endian
+// 0x06    0xa5 if serial is present
+// 0x07    flags: bit 0 remote wakeup, bit 1 IR enabled
+// 0x08    0x02
+// 0x09    manufacturer, product and serial as USB string descriptors: length, 0x03, UTF-16LE chars
+
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::thread;
+use crate::scanner::{self, ScannerStatus, ScanError};
+
+pub const EEPROM_SIZE: usize = 256;
+const STR_OFFSET: usize = 0x09;
+const HAVE_SERIAL: u8 = 0xa5;
+const STRING_DESCRIPTOR: u8 = 0x03;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Eeprom {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub have_serial: bool,
+    pub remote_wakeup: bool,
+    pub enable_ir: bool,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+}
+
+impl Eeprom {
+    pub fn parse(data: &[u8]) -> Result<Eeprom, String> {
+        if data.len() < STR_OFFSET || data[0] != 0x28 || data[1] != 0x32 {
+            return Err("Invalid RTL2832 EEPROM header".to_string());
+        }
+        let (manufacturer, pos) = read_string_descriptor(data, STR_OFFSET)?;
+        let (product, pos) = read_string_descriptor(data, pos)?;
+        let (serial, _) = read_string_descriptor(data, pos)?;
+        Ok(Eeprom {
+            vendor_id: data[2] as u16 | (data[3] as u16) << 8,
+            product_id: data[4] as u16 | (data[5] as u16) << 8,
+            have_serial: data[6] == HAVE_SERIAL,
+            remote_wakeup: data[7] & 0x01 != 0,
+            enable_ir: data[7] & 0x02 != 0,
+            manufacturer,
+            product,
+            serial,
+        })
+    }
+
+    /// Serialize over `original` content, so that the bytes after string descriptors are kept.
+    pub fn write(&self, original: &[u8]) -> Result<Vec<u8>, String> {
+        let mut data = original.to_vec();
+        data.resize(EEPROM_SIZE, 0xff);
+        data[0] = 0x28;
+        data[1] = 0x32;
+        data[2] = self.vendor_id as u8;
+        data[3] = (self.vendor_id >> 8) as u8;
+        data[4] = self.product_id as u8;
+        data[5] = (self.product_id >> 8) as u8;
+        data[6] = if self.have_serial { HAVE_SERIAL } else { 0x00 };
+        data[7] = (self.remote_wakeup as u8) | (self.enable_ir as u8) << 1;
+        data[8] = 0x02;
+        let pos = write_string_descriptor(&mut data, STR_OFFSET, &self.manufacturer)?;
+        let pos = write_string_descriptor(&mut data, pos, &self.product)?;
+        write_string_descriptor(&mut data, pos, &self.serial)?;
+        Ok(data)
+    }
+}
+
+fn read_string_descriptor(data: &[u8], pos: usize) -> Result<(String, usize), String> {
+    if pos + 2 > data.len() {
+        return Err(format!("String descriptor at {} is out of EEPROM", pos));
+    }
+    let len = data[pos] as usize;
+    if data[pos + 1] != STRING_DESCRIPTOR {
+        return Err(format!("Invalid string descriptor at {}", pos));
+    }
+    if len < 2 || pos + len > data.len() {
+        return Err(format!("String descriptor at {} is too long", pos));
+    }
+    let str = data[pos+2..pos+len].iter().step_by(2).map(|&c| c as char).collect();
+    Ok((str, pos + len))
+}
+
+fn write_string_descriptor(data: &mut Vec<u8>, pos: usize, str: &str) -> Result<usize, String> {
+    if !str.is_ascii() {
+        return Err(format!("'{}' is not ASCII", str));
+    }
+    let len = 2 + str.len() * 2;
+    if len > 0xff || pos + len > EEPROM_SIZE {
+        return Err(format!("'{}' does not fit into EEPROM", str));
+    }
+    data[pos] = len as u8;
+    data[pos + 1] = STRING_DESCRIPTOR;
+    for (i, c) in str.bytes().enumerate() {
+        data[pos + 2 + i*2] = c;
+        data[pos + 3 + i*2] = 0;
+    }
+    Ok(pos + len)
+}
+
+/// Rewrite serial of the device `current` at `index` in the background. Reports the outcome to the
+/// returned queue, followed by `ScannerStatus::Complete`.
+pub fn start(current: String, index: i32, serial: String) -> Arc<Mutex<VecDeque<ScannerStatus>>> {
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    let queue2 = queue.clone();
+    thread::spawn(move || {
+        let status = match write_serial(&current, index, &serial) {
+            Ok(()) => ScannerStatus::Info(format!("Serial {} written, re-plug the device to apply", serial)),
+            Err(err) => ScannerStatus::Error(ScanError::Eeprom(err)),
+        };
+        let mut queue2 = queue2.lock().unwrap();
+        queue2.push_back(status);
+        queue2.push_back(ScannerStatus::Complete);
+    });
+    queue
+}
+
+/// Rewrite serial number of the device `current` at `index`. Takes effect after the device is
+/// re-plugged.
+pub fn write_serial(current: &str, index: i32, serial: &str) -> Result<(), String> {
+    if serial.is_empty() {
+        return Err("Serial can not be empty".to_string());
+    }
+    // Index may be stale after re-plugging, find the device again right before writing
+    let mut driver = scanner::open_device(current, index).map_err(|e| e.to_string())?;
+    let original = driver.read_eeprom(0, EEPROM_SIZE as u16).map_err(|e| e.to_string())?;
+    let mut eeprom = Eeprom::parse(&original)?;
+    eeprom.serial = serial.to_string();
+    eeprom.have_serial = true;
+    let data = eeprom.write(&original)?;
+    driver.write_eeprom(&data, 0).map_err(|e| e.to_string())?;
+
+    // Write is not verified by the driver
+    let written = driver.read_eeprom(0, data.len() as u16).map_err(|e| e.to_string())?;
+    if written != data {
+        return Err("EEPROM content read back differs from what was written".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eeprom() -> Eeprom {
+        Eeprom {
+            vendor_id: 0x0bda,
+            product_id: 0x2838,
+            have_serial: true,
+            remote_wakeup: false,
+            enable_ir: true,
+            manufacturer: "Realtek".to_string(),
+            product: "RTL2838UHIDIR".to_string(),
+            serial: "00000001".to_string(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = eeprom().write(&[]).unwrap();
+        assert_eq!(EEPROM_SIZE, data.len());
+        assert_eq!(&[0x28, 0x32, 0xda, 0x0b, 0x38, 0x28, 0xa5, 0x02], &data[..8]);
+        assert_eq!(eeprom(), Eeprom::parse(&data).unwrap());
+    }
+
+    #[test]
+    fn change_serial_keeps_tail() {
+        let mut original = eeprom().write(&[]).unwrap();
+        original[200] = 0x42;
+        let mut changed = Eeprom::parse(&original).unwrap();
+        changed.serial = "RACK0002".to_string();
+        let data = changed.write(&original).unwrap();
+        assert_eq!("RACK0002", Eeprom::parse(&data).unwrap().serial);
+        assert_eq!(0x42, data[200]);
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(Eeprom::parse(&[0; EEPROM_SIZE]).is_err());
+    }
+
+    #[test]
+    fn serial_too_long() {
+        let mut e = eeprom();
+        e.serial = "x".repeat(200);
+        assert!(e.write(&[]).is_err());
+    }
+}
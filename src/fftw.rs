@@ -1,10 +1,15 @@
 use libc::*;
 use std::*;
+use std::sync::Mutex;
 
 pub static FFTW_FORWARD: c_int = -1;
 pub static FFTW_MEASURE: c_uint = 0;
 pub static FFTW_ESTIMATE: c_uint = 1 << 6;
 
+/// Only fftw_execute is thread safe, the planner must not be used from two threads at once.
+/// Devices sweep in their own threads, so creating and destroying plans is serialized here.
+static PLANNER: Mutex<()> = Mutex::new(());
+
 
 #[link(name="fftw3")]
 extern {
@@ -27,24 +32,39 @@ pub struct Plan {
 }
 
 impl Plan {
-    pub fn new(n: usize) -> Plan {
+    /// None if FFTW fails to allocate buffers or create the plan
+    pub fn new(n: usize) -> Option<Plan> {
         unsafe {
             // From fftw doc: we recommend using fftw_malloc, which behaves like malloc except that it
             // properly aligns the array when SIMD instructions
             let input = fftw_malloc(n*8*2);
             let output = fftw_malloc(n*8*2);
 
-            if input.is_null() || output.is_null() {panic!("fftw_malloc failed")}
-            let plan_ptr = fftw_plan_dft_1d(n as c_int, input, output, FFTW_FORWARD, FFTW_MEASURE);
-            Plan {
+            let plan_ptr = if input.is_null() || output.is_null() {
+                ptr::null_mut()
+            } else {
+                let _planner = PLANNER.lock().unwrap_or_else(|e| e.into_inner());
+                fftw_plan_dft_1d(n as c_int, input, output, FFTW_FORWARD, FFTW_MEASURE)
+            };
+            if plan_ptr.is_null() {
+                if !input.is_null() { fftw_free(input) }
+                if !output.is_null() { fftw_free(output) }
+                return None;
+            }
+            Some(Plan {
                 fftw_plan: plan_ptr,
                 input: input as *mut f64,
                 output: output as *mut f64,
                 len: n
-            }
+            })
         }
     }
 
+    /// Transform size, samples
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
     pub fn execute(&self) {
         unsafe {fftw_execute(self.fftw_plan)}
     }
@@ -62,7 +82,10 @@ impl Plan {
 impl Drop for Plan {
     fn drop(&mut self) {
         unsafe {
-            fftw_destroy_plan(self.fftw_plan);
+            {
+                let _planner = PLANNER.lock().unwrap_or_else(|e| e.into_inner());
+                fftw_destroy_plan(self.fftw_plan);
+            }
             fftw_free(self.input as *mut u8);
             fftw_free(self.output as *mut u8);
         }
@@ -76,7 +99,7 @@ mod tests {
     use super::*;
     #[test]
     fn executes() {
-        let p = Plan::new(10);
+        let p = Plan::new(10).unwrap();
         p.execute();
     }
 }
\ No newline at end of file
@@ -3,47 +3,475 @@ use rtlsdr::USBStrings;
 use std::collections::VecDeque;
 use imgui::ImString;
 use rtlsdr::RTLSDRError;
-use crate::scanner::{Scanner, ScannerStatus};
+use crate::scanner::{Scanner, ScannerStatus, ScanDevice};
+use crate::correction::CorrectionSettings;
+use crate::sweep::SweepStrategy;
+use crate::settings::{Settings, GuiSettings};
+use crate::scan_plan::{ScanPlan, PlanRange};
+use crate::mask::{FreqRange, Spur};
+use crate::adaptive::AdaptiveSettings;
+use crate::zoom::ZoomSettings;
+use crate::realtime::RealtimeSettings;
+use crate::charts::{Colormap, Chart, Trace, Interaction};
+use crate::waterfall::Waterfall;
+use crate::rules::Rule;
+use crate::recording::{RecordingSettings, RecordingFormat};
+use crate::archive::{self, ArchiveSettings, Statistics};
+use crate::replay::Replay;
+use crate::samples::Spectrum;
+use crate::markers::{self, Marker};
+use crate::sigmf;
+use crate::calibration;
+use crate::eeprom;
+use crate::tuner::{DirectSampling, TunerRange, DIRECT_SAMPLING_MIN};
 use futures::sync::mpsc::UnboundedReceiver;
-use std::sync::{Arc, Mutex};
-use crate::{SAMPLERATE, DWELL_MS, BANDWIDTH};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::path::Path;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{SAMPLERATE, BANDWIDTH};
 
 const LOG_LEN: usize = 100;
+const PATH_LEN: usize = 256;
+const NAME_LEN: usize = 64;
+const MAX_MARKERS: usize = 8;
+/// GUI settings are saved once they stop changing for this long
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+const TRACE: (f32, f32, f32, f32) = (0.9, 0.9, 0.9, 1.0);
+const MAX_TRACE: (f32, f32, f32, f32) = (1.0, 0.6, 0.2, 1.0);
+/// Rows of real-time waterfall
+const WATERFALL_ROWS: usize = 256;
+const WATERFALL_COLUMNS: usize = 1024;
+const WATERFALL_HEIGHT: f32 = 256.0;
 
 pub(crate) struct State {
     pub show_log: bool,
     // TODO: do colors for errors
     pub log: VecDeque<String>,
     pub devices: Vec<Device>,
-    pub selected_device: usize,
+    /// Serials of devices used for scanning, the range is split between them
+    pub selected_devices: Vec<String>,
     pub scan_from: u32,
     pub scan_to: u32,
+    pub dwell_ms: usize,
+    pub correction: CorrectionSettings,
+    pub sweep_strategy: SweepStrategy,
+    /// Offset used by `SweepStrategy::Offset`, kept separately to survive switching strategies
+    pub tuning_offset: u32,
+    /// Carrier used for frequency calibration, Hz
+    pub calibration_reference: u32,
+    /// ADC branch used below tuner's range
+    pub direct_sampling: DirectSampling,
+    /// Revisit steps with signals after a coarse sweep
+    pub adaptive_enabled: bool,
+    pub adaptive: AdaptiveSettings,
+    pub settings: Settings,
+    /// When GUI settings last differed from the saved ones
+    pub settings_changed: Option<Instant>,
+    /// Settings file failed to load and could not be moved aside, so it is not overwritten
+    pub settings_locked: bool,
+    /// Ranges swept repeatedly by "Start plan"
+    pub plan: ScanPlan,
+    /// File the plan is loaded from and saved to
+    pub plan_path: ImString,
     pub is_running: bool,
+    /// Aborts running scan when set
+    pub scanner_stop: Option<Arc<AtomicBool>>,
+    /// Serials of devices used by running scan
+    pub scanner_devices: Vec<String>,
     pub scanner_cmd: Option<Arc<Mutex<VecDeque<ScannerStatus>>>>,
-    pub data: Vec<f32>,
+    /// Current sweep
+    pub spectrum: Spectrum,
+    /// Saved sweep live data is compared to
+    pub reference: Option<Spectrum>,
+    /// Show live data as difference from `reference`
+    pub show_difference: bool,
+    /// Bins which rose more than this above `reference` are highlighted, dB
+    pub rise_threshold: f32,
+    /// Traces derived from the spectrum, reference and history
+    pub overlays: Overlays,
+    /// Markers on the full view
+    pub markers: Vec<Marker>,
+    /// Zoom, pan and scale of the full view chart
+    pub full_chart: Chart,
+    pub zoom_chart: Chart,
+    pub live_chart: Chart,
+    pub zoom_fft_size: usize,
+    pub zoom_averages: usize,
+    /// Result of the last zoom: (first bin frequency, bin width, psd)
+    pub zoom: Option<(f64, f64, Vec<f32>)>,
+    pub realtime: RealtimeSettings,
+    /// Latest spectrum of real-time mode, history is kept by `live_waterfall`
+    pub live: Option<Vec<f64>>,
+    /// First bin frequency and bin width of `live`
+    pub live_freq: (f64, f64),
+    pub live_waterfall: Waterfall,
+    /// Path and file name prefix of IQ recordings
+    pub record_path: ImString,
+    pub record_format: RecordingFormat,
+    /// Record IQ buffers of sweep steps
+    pub record_sweep: bool,
+    /// Frequency of continuous recording, Hz
+    pub record_freq: u32,
+    pub record_max_mb: f32,
+    pub record_max_s: f32,
+    /// Append sweeps to an archive named `<archive_prefix>_<time>.rtla`
+    pub archive_enabled: bool,
+    pub archive_prefix: ImString,
+    /// Archive bin width, kHz
+    pub archive_bin_khz: f32,
+    /// File > Open dialog is shown
+    pub show_open: bool,
+    /// Path edited in File > Open
+    pub open_path: ImString,
+    pub history: Option<History>,
+    /// Saved sweeps being opened by a worker thread, which reports into `opened`
+    pub opening: Option<String>,
+    pub opened: Arc<Mutex<VecDeque<(String, Result<History, io::Error>)>>>,
+    /// CSV files being exported by worker threads, which report into `exported`
+    pub exporting: Vec<String>,
+    pub exported: Arc<Mutex<VecDeque<(String, Result<usize, io::Error>)>>>,
+    /// Progress of running recording: (path, bytes, seconds)
+    pub recording: Option<(String, u64, f64)>,
+    /// Index and name of triggered rules which asked for highlight
+    pub alerts: Vec<(usize, String)>,
+    /// Waterfall color scale, dB
+    pub waterfall_floor: f32,
+    pub waterfall_ceiling: f32,
+    pub colormap: Colormap,
+    /// Regions revisited by adaptive sweep
+    pub refined: Vec<Refined>,
+}
+
+/// Region revisited by adaptive sweep
+pub(crate) struct Refined {
+    /// Frequency of the first bin, Hz
+    pub freq: f64,
+    pub bin_width: f64,
+    pub psd: Vec<f64>,
+    pub chart: Chart,
+}
+
+/// Saved sweeps opened with File > Open
+pub(crate) struct History {
+    pub path: String,
+    pub replay: Replay,
+    pub statistics: Statistics,
+    /// Scrub position, rows up to this time are shown
+    pub time: f64,
+    /// Position the slider is dragged to, rows are read when it is released
+    pub scrub_to: Option<f64>,
+    /// Rows shown in the waterfall, oldest first
+    pub rows: Vec<Vec<f32>>,
+    /// Show the row at `time` under live data
+    pub compare: bool,
+    /// Max and mean over the whole file
+    pub chart: Chart,
+    pub waterfall: Waterfall,
+}
+
+impl History {
+    /// Read the whole file for statistics and the last rows for the waterfall. Large archives take
+    /// a while, so this runs on a worker thread.
+    pub fn open(path: &str) -> Result<History, io::Error> {
+        let mut replay = Replay::open(path)?;
+        let statistics = replay.statistics()?;
+        let time = replay.time_span().map_or(0.0, |(_, last)| last);
+        let mut history = History { path: path.to_string(), replay, statistics, time, scrub_to: None, rows: vec![], compare: false,
+            chart: Chart::new(150.0), waterfall: Waterfall::new(WATERFALL_COLUMNS, WATERFALL_ROWS) };
+        history.scrub(time)?;
+        Ok(history)
+    }
+
+    /// Show rows up to `time`
+    pub fn scrub(&mut self, time: f64) -> Result<(), io::Error> {
+        self.time = time;
+        self.rows = self.replay.read_last(WATERFALL_ROWS, time)?.into_iter().
+            map(|row| row.levels.into_iter().map(|l| l as f32).collect()).
+            collect();
+        self.waterfall.clear();
+        for row in &self.rows {
+            self.waterfall.push(row);
+        }
+        Ok(())
+    }
+
+    /// Row at the scrub position
+    pub fn spectrum(&self) -> Option<Spectrum> {
+        let row = self.rows.last()?;
+        let header = self.replay.header();
+        Some(Spectrum { from: header.from, bin_width: header.bin_width, levels: row.iter().map(|&l| l as f64).collect() })
+    }
+}
+
+/// Traces of the full view derived from the spectrum, reference and history. They are recomputed
+/// only after one of those changed, which is marked by `stale`.
+#[derive(Default)]
+pub(crate) struct Overlays {
+    pub stale: bool,
+    /// Spectrum minus reference, when it is shown instead of the spectrum
+    pub difference: Option<Vec<f64>>,
+    /// Ranges which rose above the reference: (from, to, rise)
+    pub rises: Vec<(f64, f64, f64)>,
+    /// Row at the history's scrub position
+    pub history: Option<Spectrum>,
+    /// `history` on the grid of the spectrum, when compared with live data
+    pub compare: Option<Vec<f64>>,
 }
 
 pub(crate) struct Device {
+    /// Index in rtlsdr device list, changes when devices are re-plugged
+    pub index: i32,
+    /// False when device was unplugged. Device is kept in the list to keep its settings.
+    pub present: bool,
     pub name: String,
     pub usb_description: USBStrings,
+    /// Gain names for combo box, first one is automatic gain
     pub gains: Vec<ImString>,
+    /// Tuner gains, 10th of dB
+    pub gain_values: Vec<i32>,
+    /// Index in `gains`
     pub selected_gain: i32,
     pub tuner_type: String,
+    pub range: TunerRange,
+    pub ppm: i32,
+    /// Level calibration, dB
+    pub level_offset: f64,
+    /// Edited serial to be written into EEPROM
+    pub new_serial: ImString,
+    /// EEPROM write was requested and waits for confirmation
+    pub confirm_serial: bool,
 }
 
 impl State {
     pub fn new() -> Self {
-        State {
+        let (settings, settings_error) = match Settings::load() {
+            Ok(settings) => (settings, None),
+            Err(err) => (Settings::default(), Some(err)),
+        };
+        let gui = settings.gui.clone();
+        let mut state = State {
             show_log: false,
             log: VecDeque::with_capacity(100),
             devices: vec![],
-            selected_device: 0,
-            scan_from: 60e6 as u32,
-            scan_to: 1700e6 as u32,
+            selected_devices: gui.selected_devices.clone(),
+            scan_from: gui.scan_from,
+            scan_to: gui.scan_to,
+            dwell_ms: gui.dwell_ms,
+            correction: CorrectionSettings::default(),
+            sweep_strategy: SweepStrategy::Centered,
+            tuning_offset: (SAMPLERATE / 4) as u32,
+            calibration_reference: 100e6 as u32,
+            direct_sampling: DirectSampling::QBranch,
+            adaptive_enabled: false,
+            adaptive: AdaptiveSettings::default(),
+            settings,
+            settings_changed: None,
+            settings_locked: false,
+            plan: ScanPlan { ranges: vec![PlanRange::default()] },
+            plan_path: {
+                let mut path = ImString::with_capacity(PATH_LEN);
+                path.push_str("plan.json");
+                path
+            },
             is_running: false,
+            scanner_stop: None,
+            scanner_devices: vec![],
             scanner_cmd: None,
-            data: vec![],
+            spectrum: Spectrum::new(),
+            reference: None,
+            show_difference: gui.show_difference,
+            rise_threshold: gui.rise_threshold,
+            overlays: Overlays { stale: true, ..Overlays::default() },
+            markers: vec![],
+            full_chart: Chart::new(200.0),
+            zoom_chart: Chart::new(200.0),
+            live_chart: Chart::new(150.0),
+            zoom_fft_size: 8192,
+            zoom_averages: 8,
+            zoom: None,
+            realtime: RealtimeSettings::default(),
+            live: None,
+            live_waterfall: Waterfall::new(WATERFALL_COLUMNS, WATERFALL_ROWS),
+            live_freq: (0.0, 0.0),
+            record_path: {
+                let mut path = ImString::with_capacity(PATH_LEN);
+                path.push_str("capture");
+                path
+            },
+            record_format: RecordingFormat::SigmfCu8,
+            record_sweep: false,
+            record_freq: 100e6 as u32,
+            record_max_mb: 100.0,
+            record_max_s: 0.0,
+            archive_enabled: false,
+            archive_prefix: {
+                let mut path = ImString::with_capacity(PATH_LEN);
+                path.push_str("sweeps");
+                path
+            },
+            archive_bin_khz: (archive::DEFAULT_BIN_WIDTH / 1e3) as f32,
+            show_open: false,
+            open_path: ImString::with_capacity(PATH_LEN),
+            history: None,
+            opening: None,
+            opened: Arc::new(Mutex::new(VecDeque::new())),
+            exporting: vec![],
+            exported: Arc::new(Mutex::new(VecDeque::new())),
+            recording: None,
+            alerts: vec![],
+            waterfall_floor: gui.waterfall_floor,
+            waterfall_ceiling: gui.waterfall_ceiling,
+            colormap: gui.colormap,
+            refined: vec![],
+        };
+        for (name, chart) in state.charts() {
+            if let Some(settings) = gui.charts.get(name) {
+                chart.restore(settings);
+            }
         }
+        if let Some(err) = settings_error {
+            state.append_log(format!("ERROR Failed to load settings: {}", err));
+            match Settings::backup() {
+                Ok(backup) => state.append_log(format!("WARNING Moved unreadable settings to {}", backup.display())),
+                Err(err) => {
+                    state.append_log(format!("ERROR Failed to back up settings, they will not be saved: {}", err));
+                    state.settings_locked = true;
+                },
+            }
+        }
+        state
+    }
+
+    /// Charts whose scale is remembered, by name
+    fn charts(&mut self) -> Vec<(&'static str, &mut Chart)> {
+        vec![("full", &mut self.full_chart), ("zoom", &mut self.zoom_chart), ("live", &mut self.live_chart)]
+    }
+
+    fn gui_settings(&mut self) -> GuiSettings {
+        GuiSettings {
+            scan_from: self.scan_from,
+            scan_to: self.scan_to,
+            dwell_ms: self.dwell_ms,
+            selected_devices: self.selected_devices.clone(),
+            colormap: self.colormap,
+            waterfall_floor: self.waterfall_floor,
+            waterfall_ceiling: self.waterfall_ceiling,
+            show_difference: self.show_difference,
+            rise_threshold: self.rise_threshold,
+            charts: self.charts().into_iter().map(|(name, chart)| (name.to_string(), chart.settings())).collect(),
+        }
+    }
+
+    /// Called every frame. Saves GUI settings once they settle, so dragging a value does not
+    /// write the file on every frame.
+    pub fn track_gui_settings(&mut self) {
+        let gui = self.gui_settings();
+        if gui != self.settings.gui {
+            self.settings.gui = gui;
+            self.settings_changed = Some(Instant::now());
+        } else if self.settings_changed.map_or(false, |changed| changed.elapsed() >= SAVE_DELAY) {
+            self.save_settings();
+        }
+    }
+
+    /// Start scanning with selected devices. `configure` selects the mode of the scanner,
+    /// by default from-to range is swept once.
+    pub fn start_scanner<F: FnOnce(Scanner) -> Scanner>(&mut self, configure: F) {
+        let devices = self.selected().iter().map(|d| d.scan_device()).collect::<Vec<_>>();
+        if devices.is_empty() {
+            self.append_log("ERROR No device selected".to_string());
+            return;
+        }
+        self.is_running = true;
+        self.scanner_devices = devices.iter().map(|d| d.serial.clone()).collect();
+        let mut scanner = Scanner::new(
+            devices,
+            SAMPLERATE,
+            self.scan_from,
+            self.scan_to,
+            self.dwell_ms,
+            BANDWIDTH
+        ).correction(self.correction).
+            strategy(self.sweep_strategy).
+            direct_sampling(self.direct_sampling).
+            mask(self.settings.exclusions.clone(), self.settings.spurs.clone()).
+            rules(self.settings.rules.clone());
+        match Settings::alerts_dir() {
+            Some(dir) => scanner = scanner.alert_dir(dir),
+            None => self.append_log("WARNING No config directory, alerts are written to the working directory".to_string()),
+        }
+        if self.adaptive_enabled {
+            scanner = scanner.adaptive(self.adaptive);
+        }
+        if self.record_sweep {
+            scanner = scanner.record(self.recording_settings(None));
+        }
+        if self.archive_enabled {
+            scanner = scanner.archive(ArchiveSettings {
+                prefix: self.archive_prefix.to_str().to_string(),
+                bin_width: self.archive_bin_khz as f64 * 1e3,
+            });
+        }
+        self.run_scanner(configure(scanner));
+    }
+
+    /// Replay SigMF recording or saved sweeps through the scanner, without devices
+    pub fn start_replay(&mut self, path: String) {
+        self.is_running = true;
+        self.scanner_devices = vec![];
+        let scanner = Scanner::new(vec![], SAMPLERATE, self.scan_from, self.scan_to, self.dwell_ms, BANDWIDTH).
+            correction(self.correction).
+            strategy(self.sweep_strategy).
+            mask(self.settings.exclusions.clone(), self.settings.spurs.clone()).
+            source(path);
+        self.run_scanner(scanner);
+    }
+
+    fn run_scanner(&mut self, scanner: Scanner) {
+        self.scanner_stop = Some(scanner.stop_flag());
+        let rx_data = scanner.start();
+        self.scanner_cmd = Some(rx_data);
+    }
+
+    pub fn recording_settings(&self, freq: Option<u32>) -> RecordingSettings {
+        RecordingSettings {
+            path: self.record_path.to_str().to_string(),
+            format: self.record_format,
+            freq,
+            max_bytes: (self.record_max_mb.max(0.0) as f64 * 1e6) as u64,
+            max_seconds: self.record_max_s.max(0.0) as f64,
+        }
+    }
+
+    fn update_overlays(&mut self) {
+        let spectrum = &self.spectrum;
+        let difference = match &self.reference {
+            Some(reference) if self.show_difference => Some(spectrum.difference(reference)),
+            _ => None,
+        };
+        let rises = match &self.reference {
+            Some(reference) if spectrum.levels.len() > 1 => spectrum.rises(reference, self.rise_threshold as f64),
+            _ => vec![],
+        };
+        let history = self.history.as_ref().and_then(|h| h.spectrum());
+        let compare = match (self.history.as_ref().filter(|h| h.compare), &history) {
+            (Some(_), Some(trace)) => Some(spectrum.freqs().iter().
+                map(|f| trace.level_at(f + spectrum.bin_width / 2.0)).
+                collect()),
+            _ => None,
+        };
+        self.overlays = Overlays { stale: false, difference, rises, history, compare };
+    }
+
+    /// Waterfalls whose textures the renderer keeps
+    pub fn waterfalls(&mut self) -> Vec<&mut Waterfall> {
+        let mut waterfalls = vec![&mut self.live_waterfall];
+        if let Some(history) = &mut self.history {
+            waterfalls.push(&mut history.waterfall);
+        }
+        waterfalls
     }
 
     pub fn append_log(&mut self, str: String) {
@@ -52,6 +480,117 @@ impl State {
         }
         self.log.push_back(str);
     }
+
+    /// Update device list with currently attached (index, serial) devices. Returns attached devices
+    /// which are not known yet and need to be probed.
+    pub fn update_devices(&mut self, attached: &[(i32, String)]) -> Vec<(i32, String)> {
+        let mut matched = vec![false; self.devices.len()];
+        let mut new_devices = vec![];
+        let mut messages = vec![];
+
+        for (idx, serial) in attached {
+            // Dongles with the same serial are matched in enumeration order
+            let known = self.devices.iter().enumerate().
+                position(|(i, d)| !matched[i] && &d.usb_description.serial == serial);
+            match known {
+                Some(i) => {
+                    matched[i] = true;
+                    let device = &mut self.devices[i];
+                    device.index = *idx;
+                    if !device.present {
+                        device.present = true;
+                        messages.push(format!("Device {} (serial {}) reconnected", device.name, serial));
+                    }
+                },
+                None => new_devices.push((*idx, serial.clone())),
+            }
+        }
+
+        for (i, device) in self.devices.iter_mut().enumerate() {
+            if matched[i] || !device.present {
+                continue;
+            }
+            device.present = false;
+            let serial = &device.usb_description.serial;
+            messages.push(format!("Device {} (serial {}) disconnected", device.name, serial));
+            if self.scanner_devices.contains(serial) {
+                if let Some(stop) = &self.scanner_stop {
+                    stop.store(true, Ordering::Relaxed);
+                    messages.push("ERROR Scanning device is gone, aborting scan".to_string());
+                }
+            }
+        }
+
+        for msg in messages {
+            self.append_log(msg);
+        }
+        new_devices
+    }
+
+    /// Selected devices which are attached
+    pub fn selected(&self) -> Vec<&Device> {
+        self.devices.iter().
+            filter(|d| d.present && self.selected_devices.contains(&d.usb_description.serial)).
+            collect()
+    }
+
+    /// Add newly probed device, restoring its settings
+    pub fn add_device(&mut self, mut device: Device) {
+        let serial = device.usb_description.serial.clone();
+        let settings = self.settings.device(&serial);
+        device.ppm = settings.ppm;
+        device.set_gain(settings.gain);
+        device.level_offset = settings.level_offset;
+
+        self.append_log(format!("Found device {} (serial {})", device.name, serial));
+        if self.devices.iter().any(|d| d.usb_description.serial == serial) {
+            self.append_log(format!("WARNING More than one device with serial {}, settings will be shared. \
+                Write unique serial into device EEPROM.", serial));
+        }
+        self.devices.push(device);
+        // Use the first device unless one selected last time is attached. Selection of devices
+        // which are not attached is replaced, so they do not join it when plugged in later.
+        if self.selected().is_empty() {
+            self.selected_devices = vec![serial];
+        }
+    }
+
+    /// Apply frequency correction to the device and remember it for the device's serial
+    pub fn set_ppm(&mut self, serial: &str, ppm: i32) {
+        for device in self.devices.iter_mut().filter(|d| d.usb_description.serial == serial) {
+            device.ppm = ppm;
+        }
+        self.settings.device_mut(serial).ppm = ppm;
+        self.save_settings();
+    }
+
+    /// Apply gain (None for automatic) to the device and remember it for the device's serial
+    pub fn set_gain(&mut self, serial: &str, gain: Option<i32>) {
+        for device in self.devices.iter_mut().filter(|d| d.usb_description.serial == serial) {
+            device.set_gain(gain);
+        }
+        self.settings.device_mut(serial).gain = gain;
+        self.save_settings();
+    }
+
+    /// Set level calibration of the device and remember it for the device's serial
+    pub fn set_level_offset(&mut self, serial: &str, level_offset: f64) {
+        for device in self.devices.iter_mut().filter(|d| d.usb_description.serial == serial) {
+            device.level_offset = level_offset;
+        }
+        self.settings.device_mut(serial).level_offset = level_offset;
+        self.save_settings();
+    }
+
+    pub fn save_settings(&mut self) {
+        self.settings_changed = None;
+        if self.settings_locked {
+            return;
+        }
+        if let Err(err) = self.settings.save() {
+            self.append_log(format!("ERROR Failed to save settings: {}", err));
+        }
+    }
 }
 
 impl Device {
@@ -59,11 +598,40 @@ impl Device {
         let mut dev = rtlsdr::open(idx)?;
         let name = rtlsdr::get_device_name(idx);
         let usb_description = rtlsdr::get_device_usb_strings(idx)?;
-        let gains = dev.get_tuner_gains()?.iter().
+        let gain_values = dev.get_tuner_gains()?;
+        let gains = Some(ImString::new("auto")).into_iter().chain(gain_values.iter().
             // Gain is reported in 10th of Db
-            map(|gain| ImString::new(format!("{}", (*gain as f32)/10.0))).collect::<Vec<_>>();
+            map(|gain| ImString::new(format!("{}", (*gain as f32)/10.0)))).collect::<Vec<_>>();
         let (_, tuner_type) = dev.get_tuner_type();
-        Ok(Device{ name, usb_description, gains, selected_gain: 0, tuner_type })
+        let range = TunerRange::detect(&tuner_type);
+        Ok(Device{ index: idx, present: true, name, usb_description, gains, gain_values, selected_gain: 0, tuner_type, range, ppm: 0,
+            level_offset: 0.0, new_serial: ImString::with_capacity(32), confirm_serial: false })
+    }
+
+    /// Selected gain, 10th of dB. None is automatic gain.
+    pub fn gain(&self) -> Option<i32> {
+        match self.selected_gain {
+            0 => None,
+            i => self.gain_values.get(i as usize - 1).cloned(),
+        }
+    }
+
+    pub fn set_gain(&mut self, gain: Option<i32>) {
+        self.selected_gain = gain.
+            and_then(|gain| self.gain_values.iter().position(|g| *g == gain)).
+            map(|i| i as i32 + 1).
+            unwrap_or(0);
+    }
+
+    pub fn scan_device(&self) -> ScanDevice {
+        ScanDevice {
+            serial: self.usb_description.serial.clone(),
+            index: self.index,
+            ppm: self.ppm,
+            gain: self.gain(),
+            level_offset: self.level_offset,
+            range: self.range.clone(),
+        }
     }
 }
 
@@ -79,17 +647,63 @@ fn process_scanner_events(state: &mut Arc<Mutex<State>>, (width,height): (f32,f3
                     info!("{}", msg);
                     state.append_log(format!("INFO {}", msg));
                 },
-                ScannerStatus::Error(msg) => {
-                    error!("{}", msg);
-                    state.append_log(format!("ERROR {}", msg));
+                ScannerStatus::Error(err) => {
+                    error!("{}", err);
+                    state.append_log(format!("ERROR {}", err));
+                },
+                ScannerStatus::Calibrated { serial, ppm } => {
+                    info!("Device {} calibrated to {} ppm", serial, ppm);
+                    state.append_log(format!("INFO Device {} frequency correction set to {} ppm", serial, ppm));
+                    state.set_ppm(&serial, ppm);
                 },
                 ScannerStatus::Complete => {
                     state.is_running = false;
+                    state.recording = None;
+                    state.scanner_stop = None;
+                    state.scanner_devices.clear();
                     info!("Scanner complete")
                 },
-                ScannerStatus::Data(data) => {
-                    let mut data = data.into_iter().map(|d| d as f32).collect();
-                    state.data.append(&mut data);
+                ScannerStatus::SweepStarted { .. } => {
+                    state.spectrum = Spectrum::new();
+                    state.overlays.stale = true;
+                    state.refined.clear();
+                },
+                ScannerStatus::Refined { freq, bin_width, psd } => {
+                    // Adjacent refined steps make one region
+                    match state.refined.last_mut() {
+                        Some(region) if region.bin_width == bin_width &&
+                            (region.freq + region.psd.len() as f64 * bin_width - freq).abs() < bin_width => {
+                            region.psd.extend(psd);
+                        },
+                        _ => state.refined.push(Refined { freq, bin_width, psd, chart: Chart::new(80.0) }),
+                    }
+                },
+                ScannerStatus::Data { freq, bin_width, psd } => {
+                    state.spectrum.insert(freq, bin_width, &psd);
+                    state.overlays.stale = true;
+                },
+                ScannerStatus::Live { freq, bin_width, psd } => {
+                    if state.live_freq != (freq, bin_width) {
+                        state.live_waterfall.clear();
+                        state.live_freq = (freq, bin_width);
+                    }
+                    state.live_waterfall.push(&psd.iter().map(|&d| d as f32).collect::<Vec<_>>());
+                    state.live = Some(psd);
+                },
+                ScannerStatus::Alert { index, name, actions, triggered, power } => {
+                    if actions.log {
+                        let transition = if triggered { "triggered" } else { "cleared" };
+                        warn!("Rule '{}' {} at {:.1} dB", name, transition, power);
+                        state.append_log(format!("WARNING Rule '{}' {} at {:.1} dB", name, transition, power));
+                    }
+                    state.alerts.retain(|(i, _)| *i != index);
+                    if triggered && actions.highlight {
+                        state.alerts.push((index, name));
+                    }
+                },
+                ScannerStatus::Recording { path, bytes, seconds } => state.recording = Some((path, bytes, seconds)),
+                ScannerStatus::Zoom { freq, bin_width, psd } => {
+                    state.zoom = Some((freq, bin_width, psd.into_iter().map(|d| d as f32).collect()));
                 },
             }
         }
@@ -100,6 +714,8 @@ fn process_scanner_events(state: &mut Arc<Mutex<State>>, (width,height): (f32,f3
 pub(crate) fn render(ui: &Ui, state: &mut Arc<Mutex<State>>) -> bool {
     // TODO: should be integrated into support_gfx
     process_scanner_events(state, ui.get_window_size());
+    process_history_events(state);
+    state.lock().unwrap().track_gui_settings();
 
     let main_styles = vec![StyleVar::WindowRounding(0.0), StyleVar::WindowMinSize(ImVec2::new(200.0, 100.0))];
     ui.with_style_vars(&main_styles, ||{
@@ -110,28 +726,505 @@ pub(crate) fn render(ui: &Ui, state: &mut Arc<Mutex<State>>) -> bool {
             .collapsible(false)
             .movable(false)
             .title_bar(false)
+            .menu_bar(true)
             .build(|| {
+                ui.menu_bar(|| {
+                    ui.menu(im_str!("File")).build(|| {
+                        if ui.menu_item(im_str!("Open...")).build() {
+                            state.lock().unwrap().show_open = true;
+                        }
+                    });
+                });
                 render_full_view(&ui, &state);
                 ui.separator();
 
+                render_zoom(&ui, &state);
+                ui.separator();
+
+                render_realtime(&ui, &state);
+                ui.separator();
+
+                render_recording(&ui, &state);
+                ui.separator();
+
+                render_history(&ui, &state);
+                ui.separator();
+
                 render_scan(&ui, &state);
                 ui.separator();
 
+                render_plan(&ui, &state);
+                ui.separator();
+
+                render_mask(&ui, &state);
+                ui.separator();
+
+                render_rules(&ui, &state);
+                ui.separator();
+
                 render_settings(&ui, &state);
             });
     });
 
+    render_open(ui, state);
 
     true
 }
 
+/// File > Open: saved sweeps are opened for viewing, SigMF recordings are replayed
+fn render_open(ui: &Ui, state: &Arc<Mutex<State>>) {
+    let mut state = state.lock().unwrap();
+    if !state.show_open {
+        return;
+    }
+    let mut opened = true;
+    let mut open = false;
+    ui.window(im_str!("Open"))
+        .size((500.0, 110.0), ImGuiCond::FirstUseEver)
+        .opened(&mut opened)
+        .build(|| {
+            ui.text(im_str!("Sweep archive (.{}), CSV, Octave text or SigMF recording", archive::EXTENSION));
+            ui.input_text(im_str!("Path##open"), &mut state.open_path).build();
+            open = ui.small_button(im_str!("Open##file"));
+        });
+    state.show_open = opened;
+    if !open {
+        return;
+    }
+
+    let path = state.open_path.to_str().trim().to_string();
+    if sigmf::base_path(&path) != path {
+        if state.is_running {
+            state.append_log("ERROR Stop the scanner before replaying".to_string());
+            return;
+        }
+        state.start_replay(path);
+        state.show_open = false;
+        return;
+    }
+    let opened = state.opened.clone();
+    state.opening = Some(path.clone());
+    state.show_open = false;
+    thread::spawn(move || {
+        let history = History::open(&path);
+        opened.lock().unwrap().push_back((path, history));
+    });
+}
+
+/// Take saved sweeps opened and CSV exports finished by worker threads
+fn process_history_events(state: &Arc<Mutex<State>>) {
+    let mut state = state.lock().unwrap();
+    let opened = state.opened.lock().unwrap().drain(..).collect::<Vec<_>>();
+    for (path, history) in opened {
+        if state.opening.as_ref() == Some(&path) {
+            state.opening = None;
+        }
+        match history {
+            Ok(history) => {
+                state.append_log(format!("INFO Opened {} with {} sweeps", path, history.replay.rows()));
+                state.history = Some(history);
+                state.overlays.stale = true;
+            },
+            Err(err) => state.append_log(format!("ERROR Failed to open {}: {}", path, err)),
+        }
+    }
+    let exported = state.exported.lock().unwrap().drain(..).collect::<Vec<_>>();
+    for (csv, rows) in exported {
+        state.exporting.retain(|path| *path != csv);
+        match rows {
+            Ok(rows) => state.append_log(format!("INFO Exported {} sweeps to {}", rows, csv)),
+            Err(err) => state.append_log(format!("ERROR Failed to export {}: {}", csv, err)),
+        }
+    }
+}
+
 fn render_full_view(ui: &Ui, state: &Arc<Mutex<State>>) {
     if ui.collapsing_header(im_str!("Full view")).build() {
-        let points = &state.lock().unwrap().data;
+        let mut state = state.lock().unwrap();
+        // Chart borrows traces from the spectrum while its own state is updated
+        let state = &mut *state;
+        for (_, alert) in &state.alerts {
+            ui.text_colored((1.0, 0.2, 0.2, 1.0), im_str!("ALERT {}", alert));
+        }
+        if state.overlays.stale {
+            state.update_overlays();
+        }
+        let bins = state.spectrum.levels.len();
+        let (spectrum_from, bin_width) = (state.spectrum.from, state.spectrum.bin_width);
+
+        let spans = state.overlays.rises.iter().map(|&(from, to, _)| (from, to, (1.0, 0.2, 0.2, 0.3))).collect::<Vec<_>>();
+        let lines = state.markers.iter().map(|m| {
+            (m.freq, if m.delta_to.is_some() { (0.2, 0.9, 0.9, 0.9) } else { (1.0, 0.8, 0.0, 0.9) })
+        }).collect::<Vec<_>>();
+        let mut traces = vec![Trace { levels: state.overlays.difference.as_ref().unwrap_or(&state.spectrum.levels), color: TRACE }];
+        // Saved sweep being compared, on the grid of the live one
+        if let Some(compare) = &state.overlays.compare {
+            traces.push(Trace { levels: compare, color: (0.3, 0.8, 0.3, 1.0) });
+        }
+        let interaction = state.full_chart.render(ui, "chart_full", spectrum_from, bin_width, &traces, &spans, &lines);
+        match interaction {
+            Some(Interaction::Click(freq)) if bins > 1 && state.markers.len() < MAX_MARKERS => {
+                // Markers sit at bin centers
+                let bin = state.spectrum.bin(freq).unwrap_or(bins - 1);
+                let freq = state.spectrum.freq(bin) + bin_width / 2.0;
+                state.markers.push(Marker::new(freq));
+            },
+            Some(Interaction::Span(from, to)) if bins > 1 && !state.is_running => {
+                let zoom = ZoomSettings {
+                    from: from as u32,
+                    to: to as u32,
+                    fft_size: state.zoom_fft_size,
+                    averages: state.zoom_averages,
+                };
+                state.start_scanner(move |scanner| scanner.zoom(zoom));
+            },
+            _ => {},
+        }
+        if bins > 1 {
+            ui.text(im_str!("Click to place a marker, wheel to zoom, drag to pan, right-drag to zoom into a span"));
+        }
+        render_markers(ui, state);
+
+        //
+        // Reference trace
+        //
+        let mut reference_changed = false;
+        if !state.spectrum.is_empty() && ui.small_button(im_str!("Save as reference")) {
+            state.reference = Some(state.spectrum.clone());
+            reference_changed = true;
+        }
+        if state.overlays.history.is_some() {
+            ui.same_line(0.0);
+            if ui.small_button(im_str!("Use history as reference")) {
+                state.reference = state.overlays.history.clone();
+                reference_changed = true;
+            }
+        }
+        if let Some(reference) = &state.reference {
+            ui.text(im_str!("Reference {:.4}-{:.4} MHz, RBW {:.1} Hz",
+                            reference.from / 1e6, reference.to() / 1e6, reference.bin_width));
+            ui.same_line(0.0);
+            if ui.small_button(im_str!("Clear reference")) {
+                state.reference = None;
+                reference_changed = true;
+            }
+            reference_changed |= ui.checkbox(im_str!("Show difference from reference"), &mut state.show_difference);
+            let mut threshold = state.rise_threshold;
+            ui.with_item_width(200.0, || {
+                ui.input_float(im_str!("Highlight rise (dB)"), &mut threshold).step(1.0).build();
+            });
+            reference_changed |= threshold.max(0.0) != state.rise_threshold;
+            state.rise_threshold = threshold.max(0.0);
+            for (from, to, rise) in state.overlays.rises.iter().take(10) {
+                ui.text_colored((1.0, 0.2, 0.2, 1.0), im_str!("+{:.1} dB at {:.4}-{:.4} MHz", rise, from / 1e6, to / 1e6));
+            }
+        }
+
+        state.overlays.stale |= reference_changed;
+        if let Some(history) = state.history.as_ref().filter(|h| h.compare && state.overlays.compare.is_some()) {
+            ui.text_colored((0.3, 0.8, 0.3, 1.0), im_str!("Compared with {} at {}", history.path, sigmf::iso8601(history.time)));
+        }
+
+        for (idx, region) in state.refined.iter_mut().enumerate() {
+            ui.text(im_str!("Refined {:.4}-{:.4} MHz, RBW {:.0} Hz", region.freq / 1e6,
+                            (region.freq + region.psd.len() as f64 * region.bin_width) / 1e6, region.bin_width));
+            let traces = [Trace { levels: &region.psd, color: TRACE }];
+            region.chart.render(ui, &format!("chart_refined{}", idx), region.freq, region.bin_width, &traces, &[], &[]);
+        }
+    }
+}
+
+/// Marker table with peak search
+fn render_markers(ui: &Ui, state: &mut State) {
+    if state.markers.is_empty() {
+        return;
+    }
+    let readout = markers::readout(&state.markers, &state.spectrum);
+    let names = (0..state.markers.len()).map(|i| ImString::new(format!("M{}", i + 1))).collect::<Vec<_>>();
+    let mut choices = vec![im_str!("none")];
+    choices.extend(names.iter().map(|n| n.as_ref()));
+
+    enum Action { Peak, NextPeak, Left, Right, Remove }
+    let mut action = None;
+    for (i, (marker, r)) in state.markers.iter_mut().zip(&readout).enumerate() {
+        match r.delta {
+            Some((df, dl)) => ui.text(im_str!("D{} {:+.4} MHz {:+.1} dB  (M{} {:.4} MHz {:.1} dB)",
+                                              i + 1, df / 1e6, dl, marker.delta_to.unwrap() + 1, r.freq / 1e6, r.level)),
+            None => ui.text(im_str!("M{} {:.4} MHz {:.1} dB", i + 1, r.freq / 1e6, r.level)),
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Peak##marker{}", i)) { action = Some((i, Action::Peak)); }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Next peak##marker{}", i)) { action = Some((i, Action::NextPeak)); }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("<##marker{}", i)) { action = Some((i, Action::Left)); }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!(">##marker{}", i)) { action = Some((i, Action::Right)); }
+        ui.same_line(0.0);
+        let mut delta_to = marker.delta_to.map_or(0, |d| d as i32 + 1);
+        ui.with_item_width(80.0, || {
+            ui.combo(im_str!("Delta to##marker{}", i), &mut delta_to, &choices, -1);
+        });
+        marker.delta_to = if delta_to > 0 && delta_to as usize - 1 != i { Some(delta_to as usize - 1) } else { None };
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Remove##marker{}", i)) { action = Some((i, Action::Remove)); }
+    }
+    if ui.small_button(im_str!("Clear markers")) {
+        state.markers.clear();
+    }
+
+    if let Some((i, action)) = action {
+        let spectrum = &state.spectrum;
+        let levels = &spectrum.levels;
+        let bin = spectrum.bin(state.markers[i].freq).unwrap_or(0);
+        let found = match action {
+            Action::Peak => markers::peak(levels),
+            Action::NextPeak => markers::next_peak(levels, bin),
+            Action::Left => markers::next_peak_left(levels, bin),
+            Action::Right => markers::next_peak_right(levels, bin),
+            Action::Remove => {
+                markers::remove(&mut state.markers, i);
+                return;
+            },
+        };
+        if let Some(bin) = found {
+            state.markers[i].freq = spectrum.freq(bin) + spectrum.bin_width / 2.0;
+        }
+    }
+}
+
+fn render_zoom(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Zoom")).build() {
+        let mut state = state.lock().unwrap();
+        let mut fft_size = state.zoom_fft_size as i32;
+        let mut averages = state.zoom_averages as i32;
+        ui.with_item_width(200.0, || {
+            ui.input_int(im_str!("FFT size"), &mut fft_size).build();
+            ui.input_int(im_str!("Averages"), &mut averages).build();
+        });
+        state.zoom_fft_size = (fft_size.max(64) as usize).next_power_of_two();
+        state.zoom_averages = averages.max(1) as usize;
+
+        let state = &mut *state;
+        if let Some((freq, bin_width, psd)) = &state.zoom {
+            ui.text(im_str!("{:.4}-{:.4} MHz, RBW {:.1} Hz",
+                            freq / 1e6, (freq + psd.len() as f64 * bin_width) / 1e6, bin_width));
+            let levels = psd.iter().map(|&l| l as f64).collect::<Vec<_>>();
+            state.zoom_chart.render(ui, "chart_zoom", *freq, *bin_width, &[Trace { levels: &levels, color: TRACE }], &[], &[]);
+        }
+    }
+}
+
+fn render_realtime(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Real-time")).build() {
+        let mut state = state.lock().unwrap();
+        let mut freq = state.realtime.freq as f32 / 1e6;
+        let mut fft_size = state.realtime.fft_size as i32;
+        let mut overlap = (state.realtime.overlap * 100.0) as i32;
+        let mut frame_rate = state.realtime.frame_rate as f32;
+        let mut floor = state.waterfall_floor;
+        let mut ceiling = state.waterfall_ceiling;
+        ui.with_item_width(200.0, || {
+            ui.input_float(im_str!("Frequency (MHz)"), &mut freq).step(0.01).step_fast(1.0).build();
+            ui.input_int(im_str!("FFT size##realtime"), &mut fft_size).build();
+            ui.input_int(im_str!("Overlap (%)"), &mut overlap).build();
+            ui.input_float(im_str!("Frame rate (fps)"), &mut frame_rate).step(1.0).build();
+            ui.input_float(im_str!("Waterfall floor (dB)"), &mut floor).step(5.0).build();
+            ui.input_float(im_str!("Waterfall ceiling (dB)"), &mut ceiling).step(5.0).build();
+        });
+        state.realtime.freq = (freq.max(0.0) * 1e6) as u32;
+        state.realtime.fft_size = (fft_size.max(64) as usize).next_power_of_two();
+        state.realtime.overlap = overlap.max(0).min(95) as f64 / 100.0;
+        state.realtime.frame_rate = frame_rate.max(1.0).min(100.0) as f64;
+        state.waterfall_floor = floor;
+        state.waterfall_ceiling = ceiling.max(floor + 1.0);
+        render_colormap(ui, &mut state.colormap);
+
+        if state.is_running {
+            if ui.small_button(im_str!("Stop##realtime")) {
+                if let Some(stop) = &state.scanner_stop {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+        } else if ui.small_button(im_str!("Start real-time")) {
+            let realtime = state.realtime;
+            state.start_scanner(move |scanner| scanner.realtime(realtime));
+        }
+
+        let state = &mut *state;
+        if let Some(spectrum) = &state.live {
+            let (freq, bin_width) = state.live_freq;
+            let width = ui.get_window_size().0 - 15.0;
+            ui.text(im_str!("{:.4}-{:.4} MHz, RBW {:.1} Hz",
+                            freq / 1e6, (freq + spectrum.len() as f64 * bin_width) / 1e6, bin_width));
+            state.live_chart.render(ui, "chart_live", freq, bin_width, &[Trace { levels: spectrum, color: TRACE }], &[], &[]);
+
+            let (colormap, floor, ceiling) = (state.colormap, state.waterfall_floor, state.waterfall_ceiling);
+            state.live_waterfall.set_scale(colormap, floor, ceiling);
+            render_waterfall(ui, &state.live_waterfall, width);
+        }
+    }
+}
+
+/// Waterfall texture, the newest row on top. Texture appears once the renderer uploads it.
+fn render_waterfall(ui: &Ui, waterfall: &Waterfall, width: f32) {
+    match waterfall.texture() {
+        Some(texture) => {
+            let (top, bottom) = waterfall.v_range();
+            ui.image(texture, (width, WATERFALL_HEIGHT)).uv0((0.0, top)).uv1((1.0, bottom)).build();
+        },
+        None => ui.dummy((width, WATERFALL_HEIGHT)),
+    }
+}
+
+fn render_colormap(ui: &Ui, colormap: &mut Colormap) {
+    let names = Colormap::ALL.iter().map(|c| ImString::new(c.name())).collect::<Vec<_>>();
+    let names = names.iter().map(|n| n.as_ref()).collect::<Vec<&ImStr>>();
+    let mut selected = Colormap::ALL.iter().position(|c| c == colormap).unwrap_or(0) as i32;
+    ui.with_item_width(200.0, || {
+        ui.combo(im_str!("Colormap"), &mut selected, &names[..], -1);
+    });
+    *colormap = Colormap::ALL[selected as usize];
+}
+
+fn render_history(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Sweep history")).build() {
+        let mut state = state.lock().unwrap();
+        ui.checkbox(im_str!("Archive sweeps"), &mut state.archive_enabled);
+        ui.input_text(im_str!("Archive prefix"), &mut state.archive_prefix).build();
+        ui.with_item_width(200.0, || {
+            ui.input_float(im_str!("Archive resolution (kHz)"), &mut state.archive_bin_khz).step(1.0).build();
+        });
+        state.archive_bin_khz = state.archive_bin_khz.max(0.001);
+
+        if let Some(path) = &state.opening {
+            ui.text(im_str!("Opening {}...", path));
+        }
+        let path = match &state.history {
+            Some(history) => history.path.clone(),
+            None => {
+                ui.text(im_str!("Use File > Open to view saved sweeps"));
+                return;
+            }
+        };
+        let csv = Path::new(&path).with_extension("csv").to_string_lossy().to_string();
+        let exporting = state.exporting.contains(&csv);
+        let is_running = state.is_running;
+        let mut play = false;
+        let mut export = false;
+        let mut scrub_error = None;
+        // Row shown in the full view changed
+        let mut shown_changed = false;
+        let (colormap, floor, ceiling) = (state.colormap, state.waterfall_floor, state.waterfall_ceiling);
+        let history = state.history.as_mut().unwrap();
+        let header = history.replay.header().clone();
         let width = ui.get_window_size().0 - 15.0;
-        ui.plot_lines(im_str!("##chart_full"), &points[..]).
-            graph_size((width, 200.0)).
-            build();
+        ui.text(im_str!("{}: {:.4}-{:.4} MHz, RBW {:.1} Hz, {} sweeps",
+                        path, header.from / 1e6, header.to() / 1e6, header.bin_width, history.replay.rows()));
+        if let Some((first, last)) = history.replay.time_span() {
+            ui.text(im_str!("{} - {}", sigmf::iso8601(first), sigmf::iso8601(last)));
+            let mut offset = (history.scrub_to.unwrap_or(history.time) - first) as f32;
+            if ui.slider_float(im_str!("Time (s)##history"), &mut offset, 0.0, (last - first) as f32).build() {
+                history.scrub_to = Some(first + offset as f64);
+            }
+            // Rows are read once the slider is released, not on every step of the drag
+            if !ui.is_item_active() {
+                if let Some(time) = history.scrub_to.take() {
+                    scrub_error = history.scrub(time).err();
+                    shown_changed = true;
+                }
+            }
+            ui.text(im_str!("Showing sweeps up to {}", sigmf::iso8601(history.time)));
+        }
+        shown_changed |= ui.checkbox(im_str!("Compare with live data"), &mut history.compare);
+        if !is_running {
+            play = ui.small_button(im_str!("Play"));
+        }
+        if let Replay::Archive(_) = history.replay {
+            ui.same_line(0.0);
+            if exporting {
+                ui.text(im_str!("Exporting {}...", csv));
+            } else {
+                export = ui.small_button(im_str!("Export CSV"));
+            }
+        }
+
+        let mean = history.statistics.mean();
+        ui.text_colored(MAX_TRACE, im_str!("Max"));
+        ui.same_line(0.0);
+        ui.text_colored(TRACE, im_str!("Mean"));
+        let traces = [
+            Trace { levels: &history.statistics.max, color: MAX_TRACE },
+            Trace { levels: &mean, color: TRACE },
+        ];
+        history.chart.render(ui, "chart_history", header.from, header.bin_width, &traces, &[], &[]);
+        history.waterfall.set_scale(colormap, floor, ceiling);
+        render_waterfall(ui, &history.waterfall, width);
+
+        state.overlays.stale |= shown_changed;
+        if let Some(err) = scrub_error {
+            state.append_log(format!("ERROR Failed to read {}: {}", path, err));
+        }
+        if play {
+            state.start_replay(path.clone());
+        }
+        if export {
+            // Converting a long archive takes a while, so it is done on a worker thread
+            let exported = state.exported.clone();
+            state.exporting.push(csv.clone());
+            thread::spawn(move || {
+                let rows = archive::to_csv(&path, &csv);
+                exported.lock().unwrap().push_back((csv, rows));
+            });
+        }
+    }
+}
+
+fn render_recording(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("IQ recording")).build() {
+        let mut state = state.lock().unwrap();
+        ui.input_text(im_str!("File prefix"), &mut state.record_path).build();
+        let mut freq = state.record_freq as f32 / 1e6;
+        let mut max_mb = state.record_max_mb;
+        let mut max_s = state.record_max_s;
+        ui.with_item_width(200.0, || {
+            ui.input_float(im_str!("Frequency (MHz)##record"), &mut freq).step(0.01).step_fast(1.0).build();
+            ui.input_float(im_str!("Size limit (MB, 0 for none)"), &mut max_mb).step(10.0).build();
+            ui.input_float(im_str!("Time limit (s, 0 for none)"), &mut max_s).step(10.0).build();
+        });
+        state.record_freq = (freq.max(0.0) * 1e6) as u32;
+        state.record_max_mb = max_mb.max(0.0);
+        state.record_max_s = max_s.max(0.0);
+        let mut format = match state.record_format {
+            RecordingFormat::Cu8 => 0,
+            RecordingFormat::SigmfCu8 => 1,
+            RecordingFormat::SigmfCi8 => 2,
+        };
+        ui.with_item_width(200.0, || {
+            ui.combo(im_str!("Format"), &mut format,
+                     &[im_str!("cu8 + json"), im_str!("SigMF cu8"), im_str!("SigMF ci8")], -1);
+        });
+        state.record_format = match format {
+            0 => RecordingFormat::Cu8,
+            2 => RecordingFormat::SigmfCi8,
+            _ => RecordingFormat::SigmfCu8,
+        };
+        ui.checkbox(im_str!("Record IQ of sweep steps"), &mut state.record_sweep);
+
+        if state.is_running {
+            if ui.small_button(im_str!("Stop##record")) {
+                if let Some(stop) = &state.scanner_stop {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+        } else if ui.small_button(im_str!("Start recording")) {
+            let settings = state.recording_settings(Some(state.record_freq));
+            state.start_scanner(move |scanner| scanner.record(settings));
+        }
+        if let Some((path, bytes, seconds)) = &state.recording {
+            ui.text(im_str!("{}: {:.1} MB, {:.0} s", path, *bytes as f64 / 1e6, seconds));
+        }
+
     }
 }
 
@@ -148,6 +1241,75 @@ fn render_scan(ui: &Ui, state: &Arc<Mutex<State>>) {
             step(0.01).
             step_fast(1.0).
             build();
+        let mut dwell = state.dwell_ms as i32;
+        ui.input_int(im_str!("Dwell (ms)##scan"), &mut dwell).build();
+        state.dwell_ms = dwell.max(1) as usize;
+
+        //
+        // IQ correction
+        //
+        ui.checkbox(im_str!("DC correction"), &mut state.correction.dc);
+        ui.checkbox(im_str!("IQ balance"), &mut state.correction.iq_balance);
+        let mut center_bins = state.correction.center_bins as i32;
+        ui.with_item_width(70.0, || {
+            ui.input_int(im_str!("Interpolated center bins"), &mut center_bins).build();
+        });
+        state.correction.center_bins = center_bins.max(0) as usize;
+
+        //
+        // Sweep strategy
+        //
+        let mut strategy = match state.sweep_strategy {
+            SweepStrategy::Centered => 0,
+            SweepStrategy::Offset(_) => 1,
+            SweepStrategy::E4000OffsetTuning => 2,
+        };
+        let mut offset = state.tuning_offset as f32 / 1e3;
+        ui.with_item_width(200.0, || {
+            ui.combo(im_str!("Tuning"), &mut strategy,
+                     &[im_str!("Centered"), im_str!("Offset"), im_str!("E4000 offset tuning")], -1);
+        });
+        if strategy == 1 {
+            ui.input_float(im_str!("Offset (kHz)"), &mut offset).
+                step(10.0).
+                step_fast(100.0).
+                build();
+            state.tuning_offset = (offset.max(0.0) * 1e3) as u32;
+        }
+        state.sweep_strategy = match strategy {
+            1 => SweepStrategy::Offset(state.tuning_offset),
+            2 => SweepStrategy::E4000OffsetTuning,
+            _ => SweepStrategy::Centered,
+        };
+
+        ui.checkbox(im_str!("Adaptive dwell"), &mut state.adaptive_enabled);
+        if state.adaptive_enabled {
+            let mut coarse_dwell = state.adaptive.coarse_dwell_ms as i32;
+            let mut threshold = state.adaptive.threshold_db as f32;
+            let mut dwell = state.adaptive.dwell_ms as i32;
+            let mut rbw = state.adaptive.rbw as f32;
+            ui.with_item_width(200.0, || {
+                ui.input_int(im_str!("Coarse dwell (ms)"), &mut coarse_dwell).build();
+                ui.input_float(im_str!("Revisit above noise floor (dB)"), &mut threshold).step(1.0).build();
+                ui.input_int(im_str!("Revisit dwell (ms)"), &mut dwell).build();
+                ui.input_float(im_str!("Revisit RBW (Hz)"), &mut rbw).step(100.0).build();
+            });
+            state.adaptive.coarse_dwell_ms = coarse_dwell.max(1) as usize;
+            state.adaptive.threshold_db = threshold as f64;
+            state.adaptive.dwell_ms = dwell.max(1) as usize;
+            state.adaptive.rbw = rbw.max(1.0) as f64;
+        }
+
+        let mut direct_sampling = state.direct_sampling.mode();
+        ui.with_item_width(200.0, || {
+            ui.combo(im_str!("Direct sampling (HF)"), &mut direct_sampling,
+                     &[im_str!("Off"), im_str!("I branch"), im_str!("Q branch")], -1);
+        });
+        state.direct_sampling = match direct_sampling {
+            1 => DirectSampling::IBranch,
+            2 => DirectSampling::QBranch,
+            _ => DirectSampling::Off,
+        };
 
         if state.is_running {
             if ui.small_button(im_str!("Stop")) {
@@ -156,26 +1318,28 @@ fn render_scan(ui: &Ui, state: &Arc<Mutex<State>>) {
                 if let Some(mut rx_data) = rx_data {
                     debug!("UI closed receiver");
                 };*/
-                state.is_running = false;
+                // Scanner reports Complete when it stops
+                if let Some(stop) = &state.scanner_stop {
+                    stop.store(true, Ordering::Relaxed);
+                }
             }
         } else {
             if ui.small_button(im_str!("Start")) {
-                state.is_running = true;
-                let scanner = Scanner::new(
-                    state.selected_device as i32,
-                    SAMPLERATE,
-                    state.scan_from,
-                    state.scan_to,
-                    DWELL_MS,
-                    BANDWIDTH
-                );
-                let rx_data = scanner.start();
-                state.scanner_cmd = Some(rx_data);
-            }
-        }
-
-        let from = (from * 1e6) as u32;
-        let to = (to * 1e6) as u32;
+                state.start_scanner(|scanner| scanner);
+            }
+        }
+
+        // Keep the range within what selected devices can do
+        let selected = state.selected();
+        let (min, max) = if selected.is_empty() {
+            (0, std::u32::MAX)
+        } else {
+            let min = selected.iter().map(|d| d.range.min()).min().unwrap();
+            let max = selected.iter().map(|d| d.range.max()).max().unwrap();
+            if state.direct_sampling != DirectSampling::Off { (DIRECT_SAMPLING_MIN, max) } else { (min, max) }
+        };
+        let from = ((from.max(0.0) * 1e6) as u32).max(min).min(max);
+        let to = ((to.max(0.0) * 1e6) as u32).max(from).min(max);
         if state.scan_from != from {
             state.scan_from = from;
         }
@@ -185,41 +1349,361 @@ fn render_scan(ui: &Ui, state: &Arc<Mutex<State>>) {
     };
 }
 
+fn render_plan(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Scan plan")).build() {
+        let state = &mut state.lock().unwrap();
+
+        let mut remove = None;
+        for (idx, range) in state.plan.ranges.iter_mut().enumerate() {
+            ui.tree_node(im_str!("{}###range{}", range.name, idx)).build(|| {
+                let mut name = ImString::with_capacity(NAME_LEN);
+                name.push_str(&range.name);
+                if ui.input_text(im_str!("Name"), &mut name).build() {
+                    range.name = name.to_str().to_string();
+                }
+                ui.checkbox(im_str!("Enabled"), &mut range.enabled);
+
+                let mut from = range.from as f32 / 1e6;
+                let mut to = range.to as f32 / 1e6;
+                let mut dwell = range.dwell_ms as i32;
+                let mut rbw = range.rbw as f32 / 1e3;
+                let mut gain = range.gain.map(|g| g as f32 / 10.0).unwrap_or(-1.0);
+                let mut revisit = range.revisit_s as f32;
+                let mut priority = range.priority as i32;
+                ui.with_item_width(200.0, || {
+                    ui.input_float(im_str!("From (MHz)"), &mut from).step(0.01).step_fast(1.0).build();
+                    ui.input_float(im_str!("To (MHz)"), &mut to).step(0.01).step_fast(1.0).build();
+                    ui.input_int(im_str!("Dwell (ms)"), &mut dwell).build();
+                    ui.input_float(im_str!("RBW (kHz)"), &mut rbw).step(0.1).step_fast(1.0).build();
+                    ui.input_float(im_str!("Gain (dB, -1 for device gain)"), &mut gain).step(1.0).step_fast(10.0).build();
+                    ui.input_float(im_str!("Revisit every (s)"), &mut revisit).step(1.0).step_fast(10.0).build();
+                    ui.input_int(im_str!("Priority"), &mut priority).build();
+                });
+                range.from = (from.max(0.0) * 1e6) as u32;
+                range.to = ((to.max(0.0) * 1e6) as u32).max(range.from);
+                range.dwell_ms = dwell.max(1) as usize;
+                // Frames shorter than 1us make no sense at any sample rate
+                range.rbw = (rbw.max(0.001) * 1e3).min(1e6) as f64;
+                range.gain = if gain < 0.0 { None } else { Some((gain * 10.0).round() as i32) };
+                range.revisit_s = revisit.max(0.0) as f64;
+                range.priority = priority.max(0) as u32;
+
+                if ui.small_button(im_str!("Remove")) {
+                    remove = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove {
+            state.plan.ranges.remove(idx);
+        }
+        if ui.small_button(im_str!("Add range")) {
+            state.plan.ranges.push(PlanRange::default());
+        }
+
+        ui.input_text(im_str!("Plan file"), &mut state.plan_path).build();
+        if ui.small_button(im_str!("Load")) {
+            match ScanPlan::load(Path::new(state.plan_path.to_str())) {
+                Ok(plan) => {
+                    state.append_log(format!("INFO Loaded {} ranges from {}", plan.ranges.len(), state.plan_path.to_str()));
+                    state.plan = plan;
+                },
+                Err(err) => state.append_log(format!("ERROR Failed to load plan: {}", err)),
+            }
+        }
+        ui.same_line(0.0);
+        if ui.small_button(im_str!("Save")) {
+            if let Err(err) = state.plan.save(Path::new(state.plan_path.to_str())) {
+                state.append_log(format!("ERROR Failed to save plan: {}", err));
+            }
+        }
+
+        if !state.is_running && ui.small_button(im_str!("Start plan")) {
+            let plan = state.plan.clone();
+            state.start_scanner(move |scanner| scanner.plan(plan));
+        }
+    }
+}
+
+fn render_mask(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Exclusions and spurs")).build() {
+        let state = &mut state.lock().unwrap();
+        let mut changed = false;
+
+        ui.tree_node(im_str!("Excluded ranges (not swept)")).build(|| {
+            let mut remove = None;
+            for (idx, range) in state.settings.exclusions.iter_mut().enumerate() {
+                let mut from = range.from as f32 / 1e6;
+                let mut to = range.to as f32 / 1e6;
+                ui.with_item_width(120.0, || {
+                    changed |= ui.input_float(im_str!("From (MHz)##exclusion{}", idx), &mut from).step(0.1).build();
+                    ui.same_line(0.0);
+                    changed |= ui.input_float(im_str!("To (MHz)##exclusion{}", idx), &mut to).step(0.1).build();
+                });
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Remove##exclusion{}", idx)) {
+                    remove = Some(idx);
+                }
+                range.from = (from.max(0.0) * 1e6) as u32;
+                range.to = ((to.max(0.0) * 1e6) as u32).max(range.from);
+            }
+            if let Some(idx) = remove {
+                state.settings.exclusions.remove(idx);
+                changed = true;
+            }
+            if ui.small_button(im_str!("Add exclusion")) {
+                // Broadcast FM is the usual suspect
+                state.settings.exclusions.push(FreqRange { from: 88e6 as u32, to: 108e6 as u32 });
+                changed = true;
+            }
+        });
+
+        ui.tree_node(im_str!("Spur mask")).build(|| {
+            let mut remove = None;
+            for (idx, spur) in state.settings.spurs.iter_mut().enumerate() {
+                let mut freq = spur.freq as f32 / 1e6;
+                let mut width = spur.width as f32 / 1e3;
+                ui.with_item_width(120.0, || {
+                    changed |= ui.input_float(im_str!("Frequency (MHz)##spur{}", idx), &mut freq).step(0.001).build();
+                    ui.same_line(0.0);
+                    changed |= ui.input_float(im_str!("Width (kHz)##spur{}", idx), &mut width).step(1.0).build();
+                });
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Remove##spur{}", idx)) {
+                    remove = Some(idx);
+                }
+                spur.freq = (freq.max(0.0) * 1e6) as u32;
+                spur.width = (width.max(0.0) * 1e3) as u32;
+            }
+            if let Some(idx) = remove {
+                state.settings.spurs.remove(idx);
+                changed = true;
+            }
+            if ui.small_button(im_str!("Add spur")) {
+                // Crystal harmonic
+                state.settings.spurs.push(Spur { freq: 28.8e6 as u32, width: 5_000 });
+                changed = true;
+            }
+        });
+
+        if changed {
+            state.save_settings();
+        }
+        if state.is_running {
+            ui.text(im_str!("Changes apply to the next scan"));
+        }
+    }
+}
+
+fn render_rules(ui: &Ui, state: &Arc<Mutex<State>>) {
+    if ui.collapsing_header(im_str!("Alert rules")).build() {
+        let state = &mut state.lock().unwrap();
+        let mut changed = false;
+        let mut remove = None;
+        let alerts = state.alerts.clone();
+        let (scan_from, scan_to) = (state.scan_from, state.scan_to);
+        // Only sweeps feed the rules. Plain scans keep sweeping while a rule is enabled, so rules
+        // needing several sweeps in a row can trigger.
+        ui.text(im_str!("Rules are evaluated by scans and scan plans, zoom and real-time do not trigger them"));
+        for (idx, rule) in state.settings.rules.iter_mut().enumerate() {
+            let status = if alerts.iter().any(|(i, _)| *i == idx) { " (ALERT)" } else { "" };
+            ui.tree_node(im_str!("{}{}###rule{}", rule.name, status, idx)).build(|| {
+                if rule.enabled && (rule.to <= scan_from || rule.from >= scan_to) {
+                    ui.text_colored((1.0, 0.6, 0.2, 1.0), im_str!("Outside of the scan range, only a scan plan can trigger it"));
+                }
+                let mut name = ImString::with_capacity(NAME_LEN);
+                name.push_str(&rule.name);
+                if ui.input_text(im_str!("Name"), &mut name).build() {
+                    rule.name = name.to_str().to_string();
+                    changed = true;
+                }
+                changed |= ui.checkbox(im_str!("Enabled"), &mut rule.enabled);
+
+                let mut from = rule.from as f32 / 1e6;
+                let mut to = rule.to as f32 / 1e6;
+                let mut threshold = rule.threshold_db as f32;
+                let mut sweeps = rule.sweeps as i32;
+                let mut hysteresis = rule.hysteresis_db as f32;
+                ui.with_item_width(200.0, || {
+                    changed |= ui.input_float(im_str!("From (MHz)"), &mut from).step(0.001).step_fast(1.0).build();
+                    changed |= ui.input_float(im_str!("To (MHz)"), &mut to).step(0.001).step_fast(1.0).build();
+                    changed |= ui.input_float(im_str!("Threshold (dB)"), &mut threshold).step(1.0).build();
+                    changed |= ui.input_int(im_str!("Sweeps"), &mut sweeps).build();
+                    changed |= ui.input_float(im_str!("Hysteresis (dB)"), &mut hysteresis).step(0.5).build();
+                });
+                rule.from = (from.max(0.0) * 1e6) as u32;
+                rule.to = ((to.max(0.0) * 1e6) as u32).max(rule.from);
+                rule.threshold_db = threshold as f64;
+                rule.sweeps = sweeps.max(1) as usize;
+                rule.hysteresis_db = hysteresis.max(0.0) as f64;
+
+                changed |= ui.checkbox(im_str!("Log"), &mut rule.actions.log);
+                changed |= ui.checkbox(im_str!("Highlight"), &mut rule.actions.highlight);
+                changed |= ui.checkbox(im_str!("Record event"), &mut rule.actions.record);
+                changed |= ui.checkbox(im_str!("Save IQ"), &mut rule.actions.save_iq);
+                let mut command = ImString::with_capacity(PATH_LEN);
+                command.push_str(&rule.actions.command);
+                if ui.input_text(im_str!("Command"), &mut command).build() {
+                    rule.actions.command = command.to_str().to_string();
+                    changed = true;
+                }
+
+                if ui.small_button(im_str!("Remove")) {
+                    remove = Some(idx);
+                }
+            });
+        }
+        if let Some(idx) = remove {
+            state.settings.rules.remove(idx);
+            state.alerts.retain(|(i, _)| *i != idx);
+            for (i, _) in &mut state.alerts {
+                if *i > idx {
+                    *i -= 1;
+                }
+            }
+            changed = true;
+        }
+        if ui.small_button(im_str!("Add rule")) {
+            state.settings.rules.push(Rule::default());
+            changed = true;
+        }
+        if changed {
+            state.save_settings();
+        }
+        if state.is_running {
+            ui.text(im_str!("Changes apply to the next scan"));
+        }
+    }
+}
+
 fn render_settings(ui: &Ui, state: &Arc<Mutex<State>>) {
     if ui.collapsing_header(im_str!("Settings")).build() {
         let state = &mut state.lock().unwrap();
 
         ui.tree_node(im_str!("Devices")).build(|| {
-            let mut selected_device = state.selected_device;
+            let mut selected_devices = state.selected_devices.clone();
+            let mut ppm_changed = None;
+            let mut level_changed = None;
+            let mut gain_changed = None;
+            let mut serial_write = None;
+            let mut calibrate = None;
+            let mut reference = state.calibration_reference as f32 / 1e6;
+            let is_running = state.is_running;
             for (idx, device) in state.devices.iter_mut().enumerate() {
                 //
                 // Device
                 //
-                ui.tree_node(im_str!("{} {}", idx+1, device.name)).build(|| {
+                let status = if device.present { "" } else { " (disconnected)" };
+                ui.tree_node(im_str!("{} {}{}###device{}", idx+1, device.name, status, idx)).build(|| {
 
-                    let mut selected = selected_device == idx;
+                    let serial = &device.usb_description.serial;
+                    let mut selected = selected_devices.contains(serial);
                     if ui.checkbox(im_str!("Input"), &mut selected) {
-                        selected_device = idx;
+                        selected_devices.retain(|s| s != serial);
+                        if selected {
+                            selected_devices.push(serial.clone());
+                        }
                     }
 
                     ui.text(im_str!("Manufacturer: {}", device.usb_description.manufacturer));
                     ui.text(im_str!("Product: {}", device.usb_description.product));
                     ui.text(im_str!("Serial: {}", device.usb_description.serial));
                     ui.text(im_str!("Device type: {}", device.tuner_type));
+                    ui.text(im_str!("Range: {}", device.range));
 
                     //
                     // Gain
                     //
                     ui.with_item_width(70.0, || {
                         let gains = device.gains.iter().map(|gain| gain.as_ref()).collect::<Vec<_>>();
-                        ui.combo(im_str!("Gain (dB)"), &mut device.selected_gain, gains.as_slice(), -1);
+                        let mut selected_gain = device.selected_gain;
+                        if ui.combo(im_str!("Gain (dB)"), &mut selected_gain, gains.as_slice(), -1) {
+                            gain_changed = Some((idx, selected_gain));
+                        }
+                    });
+
+                    //
+                    // Frequency correction
+                    //
+                    ui.with_item_width(100.0, || {
+                        let mut ppm = device.ppm;
+                        if ui.input_int(im_str!("Frequency correction (ppm)"), &mut ppm).build() {
+                            ppm_changed = Some((device.usb_description.serial.clone(), ppm));
+                        }
+                        let mut level_offset = device.level_offset as f32;
+                        if ui.input_float(im_str!("Level offset (dB)"), &mut level_offset).step(0.1).build() {
+                            level_changed = Some((device.usb_description.serial.clone(), level_offset as f64));
+                        }
+                        ui.input_float(im_str!("Reference (MHz)"), &mut reference).
+                            step(0.001).
+                            step_fast(1.0).
+                            build();
                     });
+                    if !is_running && ui.small_button(im_str!("Calibrate")) {
+                        calibrate = Some(idx);
+                    }
+
+                    //
+                    // Serial number. Dongles often come with the same "00000001" serial, which
+                    // makes them indistinguishable.
+                    //
+                    ui.with_item_width(100.0, || {
+                        ui.input_text(im_str!("New serial"), &mut device.new_serial).build();
+                    });
+                    if device.confirm_serial {
+                        // Bad write can leave the dongle unusable, so ask before doing it
+                        ui.text(im_str!("Overwrite serial {} with \"{}\"?", serial, device.new_serial.to_str()));
+                        if !is_running && device.present && ui.small_button(im_str!("Confirm")) {
+                            serial_write = Some((serial.clone(), device.index, device.new_serial.to_str().to_string()));
+                            device.confirm_serial = false;
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Cancel")) {
+                            device.confirm_serial = false;
+                        }
+                    } else if !is_running && device.present && ui.small_button(im_str!("Write serial to EEPROM")) {
+                        device.confirm_serial = true;
+                    }
 
                     ui.separator();
                 });
             }
-            if state.selected_device != selected_device {
-                state.selected_device = selected_device;
+            if state.selected_devices != selected_devices {
+                state.selected_devices = selected_devices;
+            }
+            if let Some((serial, level_offset)) = level_changed {
+                state.set_level_offset(&serial, level_offset);
+            }
+            if let Some((idx, selected_gain)) = gain_changed {
+                let device = &mut state.devices[idx];
+                device.selected_gain = selected_gain;
+                let (serial, gain) = (device.usb_description.serial.clone(), device.gain());
+                state.set_gain(&serial, gain);
+            }
+            if let Some((current, index, serial)) = serial_write {
+                // Scanner may have been started from elsewhere meanwhile
+                if state.is_running {
+                    state.append_log("ERROR Failed to write serial: Stop the scanner first".to_string());
+                } else {
+                    state.scanner_cmd = Some(eeprom::start(current.clone(), index, serial));
+                    state.scanner_devices = vec![current];
+                    state.is_running = true;
+                }
+            }
+            // Device is tuned a quarter of samplerate below the reference
+            state.calibration_reference = ((reference.max(0.0) * 1e6) as u32).max((SAMPLERATE / 4) as u32);
+            if let Some((serial, ppm)) = ppm_changed {
+                state.set_ppm(&serial, ppm);
+            }
+            if let Some(idx) = calibrate {
+                let device = &state.devices[idx];
+                if device.present {
+                    let serial = device.usb_description.serial.clone();
+                    let queue = calibration::start(serial.clone(), device.index,
+                        SAMPLERATE, state.calibration_reference, device.ppm);
+                    state.scanner_cmd = Some(queue);
+                    state.scanner_devices = vec![serial];
+                    state.is_running = true;
+                }
             }
         });
 
@@ -2,9 +2,27 @@ use rtlsdr;
 
 mod fftw;
 mod rtl_import;
+mod correction;
+mod sweep;
+mod scan_plan;
+mod mask;
+mod adaptive;
+mod zoom;
+mod realtime;
+mod rules;
+mod recording;
+mod sigmf;
+mod archive;
+mod replay;
+mod markers;
+mod settings;
+mod calibration;
+mod tuner;
+mod eeprom;
 mod dsp;
 mod iterators;
 mod charts;
+mod decimate;
 mod samples;
 mod support_gfx;
 mod scanner;
@@ -25,7 +43,8 @@ use std::{
     iter::Iterator,
     thread,
     time::Duration,
-    cmp::Ordering
+    cmp::Ordering,
+    collections::HashSet,
 };
 use crate::gui::{render};
 
@@ -42,6 +61,7 @@ const SAMPLERATE: usize = 2e6 as usize;
 const BANDWIDTH: usize = 1e6 as usize;
 // TODO: make dwell selectable
 const DWELL_MS: usize = 16;
+const RESCAN_INTERVAL_MS: u64 = 1000;
 const CLEAR_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
 
 static dump_data: bool = true;
@@ -51,6 +71,19 @@ fn cmp_f64(_self: &f64, other: &f64) -> Ordering {
 }
 
 fn main() {
+    // rtl-scanner --archive-to-csv <archive> <csv> converts without starting the GUI
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() == 4 && args[1] == "--archive-to-csv" {
+        match archive::to_csv(&args[2], &args[3]) {
+            Ok(rows) => println!("Converted {} sweeps to {}", rows, args[3]),
+            Err(err) => {
+                eprintln!("Failed to convert {}: {}", args[2], err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     CombinedLogger::init(vec![TermLogger::new(LevelFilter::Debug, Config::default()).unwrap()]);
     let state = Arc::new(Mutex::new(State::new()));
     start_device_loop(state.clone());
@@ -71,27 +104,37 @@ fn start_device_loop(state: Arc<Mutex<State>>) {
 }
 
 fn device_loop(state: Arc<Mutex<State>>) -> Result<(),RTLSDRError> {
-    // TODO: dynamically re-scan devices
-    // TODO: detect frequency ranges
-    // TODO: detect direct sampling
+    // Serials of devices which failed to probe, they are not retried until re-plugged
+    let mut failed = HashSet::new();
 
-    let count = rtlsdr::get_device_count();
-    { state.lock().unwrap().append_log(format!("Found {} device(s)", count))}
-
-    let devices = (0..count).map(Device::probe).
-        map(|e| {
-            match e {
-                Ok(dev) => Some(dev),
+    loop {
+        let count = rtlsdr::get_device_count();
+        let attached = (0..count).filter_map(|idx| {
+            match rtlsdr::get_device_usb_strings(idx) {
+                Ok(usb) => Some((idx, usb.serial)),
                 Err(err) => {
-                    state.lock().unwrap().append_log(err.to_string());
+                    debug!("Failed to read USB strings of device {}: {}", idx, err);
                     None
                 }
             }
-        }).flatten().collect::<Vec<_>>();
+        }).collect::<Vec<_>>();
 
-    state.lock().unwrap().devices = devices;
+        let new_devices = state.lock().unwrap().update_devices(&attached);
+        failed.retain(|serial| attached.iter().any(|(_, s)| s == serial));
 
-    loop {
-        std::thread::sleep(Duration::from_secs(1));
+        for (idx, serial) in new_devices {
+            if failed.contains(&serial) {
+                continue;
+            }
+            match Device::probe(idx) {
+                Ok(device) => state.lock().unwrap().add_device(device),
+                Err(err) => {
+                    state.lock().unwrap().append_log(format!("ERROR Failed to probe device {}: {}", idx, err));
+                    failed.insert(serial);
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(RESCAN_INTERVAL_MS));
     }
 }
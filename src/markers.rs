@@ -0,0 +1,117 @@
+use crate::samples::Spectrum;
+
+/// Spectrum analyzer marker. Markers keep their frequency between sweeps and read the level of
+/// the current one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Marker {
+    /// Hz
+    pub freq: f64,
+    /// Index of the marker this one is read relative to
+    pub delta_to: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Readout {
+    pub freq: f64,
+    /// dB, -inf where there is no data
+    pub level: f64,
+    /// Frequency and level difference to the reference marker
+    pub delta: Option<(f64, f64)>,
+}
+
+impl Marker {
+    pub fn new(freq: f64) -> Marker {
+        Marker { freq, delta_to: None }
+    }
+}
+
+/// Read markers against `spectrum`
+pub fn readout(markers: &[Marker], spectrum: &Spectrum) -> Vec<Readout> {
+    markers.iter().map(|m| {
+        let level = spectrum.level_at(m.freq);
+        let delta = m.delta_to.and_then(|i| markers.get(i)).map(|r| (m.freq - r.freq, level - spectrum.level_at(r.freq)));
+        Readout { freq: m.freq, level, delta }
+    }).collect()
+}
+
+/// Remove marker `i`, markers which were relative to it become absolute
+pub fn remove(markers: &mut Vec<Marker>, i: usize) {
+    markers.remove(i);
+    for m in markers.iter_mut() {
+        m.delta_to = match m.delta_to {
+            Some(d) if d == i => None,
+            Some(d) if d > i => Some(d - 1),
+            d => d,
+        };
+    }
+}
+
+/// Bins higher than their neighbours. Flat tops count once, at their first bin.
+pub fn local_peaks(levels: &[f64]) -> Vec<usize> {
+    let mut peaks = vec![];
+    for (k, &level) in levels.iter().enumerate().filter(|(_, l)| l.is_finite()) {
+        let left = if k > 0 { levels[k - 1] } else { std::f64::NEG_INFINITY };
+        let right = levels[k + 1..].iter().cloned().find(|&r| r != level).unwrap_or(std::f64::NEG_INFINITY);
+        if left < level && right < level {
+            peaks.push(k);
+        }
+    }
+    peaks
+}
+
+/// Highest bin
+pub fn peak(levels: &[f64]) -> Option<usize> {
+    levels.iter().enumerate().filter(|(_, l)| l.is_finite()).max_by(|a, b| crate::cmp_f64(a.1, b.1)).map(|(k, _)| k)
+}
+
+/// Highest peak lower than the level at `bin`
+pub fn next_peak(levels: &[f64], bin: usize) -> Option<usize> {
+    let current = *levels.get(bin)?;
+    local_peaks(levels).into_iter().
+        filter(|&k| levels[k] < current).
+        max_by(|&a, &b| crate::cmp_f64(&levels[a], &levels[b]))
+}
+
+/// Nearest peak below `bin`
+pub fn next_peak_left(levels: &[f64], bin: usize) -> Option<usize> {
+    local_peaks(levels).into_iter().filter(|&k| k < bin).last()
+}
+
+/// Nearest peak above `bin`
+pub fn next_peak_right(levels: &[f64], bin: usize) -> Option<usize> {
+    local_peaks(levels).into_iter().find(|&k| k > bin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [f64; 8] = [-90.0, -50.0, -80.0, -60.0, -60.0, -85.0, -40.0, -70.0];
+
+    #[test]
+    fn peak_search() {
+        assert_eq!(vec![1, 3, 6], local_peaks(&LEVELS));
+        assert_eq!(Some(6), peak(&LEVELS));
+        assert_eq!(Some(1), next_peak(&LEVELS, 6));
+        assert_eq!(Some(3), next_peak(&LEVELS, 1));
+        assert_eq!(None, next_peak(&LEVELS, 3));
+        assert_eq!(Some(3), next_peak_left(&LEVELS, 6));
+        assert_eq!(Some(6), next_peak_right(&LEVELS, 3));
+        assert_eq!(None, next_peak_right(&LEVELS, 6));
+    }
+
+    #[test]
+    fn delta_readout() {
+        let mut spectrum = Spectrum::new();
+        spectrum.insert(100.0, 10.0, &LEVELS);
+        let mut markers = vec![Marker::new(115.0), Marker::new(165.0), Marker { freq: 135.0, delta_to: Some(1) }];
+        let readout = readout(&markers, &spectrum);
+        assert_eq!(-50.0, readout[0].level);
+        assert_eq!(Some((-30.0, -20.0)), readout[2].delta);
+
+        remove(&mut markers, 0);
+        assert_eq!(Some(0), markers[1].delta_to);
+        remove(&mut markers, 0);
+        assert_eq!(None, markers[0].delta_to);
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Serialize, Deserialize};
+
+/// Frequency range [from, to), Hz
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FreqRange {
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Known spur (birdie) of the dongle, masked out of results
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Spur {
+    /// Hz
+    pub freq: u32,
+    /// Masked width around `freq`, Hz
+    pub width: u32,
+}
+
+impl FreqRange {
+    pub fn contains(&self, freq: f64) -> bool {
+        freq >= self.from as f64 && freq < self.to as f64
+    }
+}
+
+impl Spur {
+    pub fn range(&self) -> FreqRange {
+        let half = self.width / 2;
+        FreqRange { from: self.freq.saturating_sub(half), to: self.freq.saturating_add(half.max(1)) }
+    }
+}
+
+/// First frequency at or above `freq` which is not excluded. Overlapping and adjacent exclusions
+/// are skipped together.
+pub fn skip_excluded(freq: u32, exclusions: &[FreqRange]) -> u32 {
+    let mut freq = freq;
+    while let Some(range) = exclusions.iter().find(|r| r.contains(freq as f64)) {
+        freq = range.to;
+    }
+    freq
+}
+
+/// Apply exclusions and spur mask to `psd` whose first bin is at `freq`. Excluded bins are set to
+/// -inf (no data), spur bins are interpolated from the neighbour bins.
+pub fn apply(psd: &mut [f64], freq: f64, bin_width: f64, exclusions: &[FreqRange], spurs: &[Spur]) {
+    let bin_freq = |k: usize| freq + (k as f64 + 0.5) * bin_width;
+    let spurs = spurs.iter().map(Spur::range).collect::<Vec<_>>();
+    let masked = (0..psd.len()).map(|k| spurs.iter().any(|r| r.contains(bin_freq(k)))).collect::<Vec<_>>();
+
+    let mut k = 0;
+    while k < psd.len() {
+        if !masked[k] {
+            k += 1;
+            continue;
+        }
+        let start = k;
+        while k < psd.len() && masked[k] {
+            k += 1;
+        }
+        let left = if start > 0 { Some(psd[start - 1]) } else { None };
+        let right = if k < psd.len() { Some(psd[k]) } else { None };
+        let (left, right) = match (left, right) {
+            (Some(l), Some(r)) => (l, r),
+            (Some(l), None) => (l, l),
+            (None, Some(r)) => (r, r),
+            (None, None) => (std::f64::NEG_INFINITY, std::f64::NEG_INFINITY),
+        };
+        let n = (k - start + 1) as f64;
+        for (i, p) in psd[start..k].iter_mut().enumerate() {
+            *p = left + (right - left) * (i + 1) as f64 / n;
+        }
+    }
+
+    for (i, p) in psd.iter_mut().enumerate() {
+        if exclusions.iter().any(|r| r.contains(bin_freq(i))) {
+            *p = std::f64::NEG_INFINITY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_overlapping_exclusions() {
+        let exclusions = [FreqRange { from: 100, to: 200 }, FreqRange { from: 150, to: 300 }];
+        assert_eq!(50, skip_excluded(50, &exclusions));
+        assert_eq!(300, skip_excluded(100, &exclusions));
+        assert_eq!(300, skip_excluded(250, &exclusions));
+    }
+
+    #[test]
+    fn spur_is_interpolated() {
+        let mut psd = vec![-80.0, -70.0, -10.0, -60.0, -50.0];
+        apply(&mut psd, 0.0, 10.0, &[], &[Spur { freq: 25, width: 10 }]);
+        assert_eq!(vec![-80.0, -70.0, -65.0, -60.0, -50.0], psd);
+    }
+
+    #[test]
+    fn excluded_bins_have_no_data() {
+        let mut psd = vec![-80.0; 4];
+        apply(&mut psd, 0.0, 10.0, &[FreqRange { from: 20, to: 40 }], &[]);
+        assert_eq!(-80.0, psd[1]);
+        assert_eq!(std::f64::NEG_INFINITY, psd[2]);
+        assert_eq!(std::f64::NEG_INFINITY, psd[3]);
+    }
+}
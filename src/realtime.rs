@@ -0,0 +1,76 @@
+/// Continuous spectrum of one tuning, like FFT view of GQRX
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RealtimeSettings {
+    /// Center frequency, Hz
+    pub freq: u32,
+    pub fft_size: usize,
+    /// Fraction of FFT frame shared with the previous frame, 0..1
+    pub overlap: f64,
+    /// Spectra reported per second, frames in between are averaged
+    pub frame_rate: f64,
+}
+
+impl Default for RealtimeSettings {
+    fn default() -> Self {
+        RealtimeSettings { freq: 100e6 as u32, fft_size: 4096, overlap: 0.5, frame_rate: 25.0 }
+    }
+}
+
+impl RealtimeSettings {
+    /// Samples between starts of consecutive FFT frames
+    pub fn hop(&self) -> usize {
+        ((self.fft_size as f64 * (1.0 - self.overlap.max(0.0).min(0.95))).round() as usize).max(1)
+    }
+}
+
+/// Cuts continuous stream of interleaved complex samples into overlapping frames
+#[derive(Debug)]
+pub struct Overlap {
+    fft_size: usize,
+    hop: usize,
+    /// Samples not consumed by frames yet, interleaved
+    pending: Vec<f64>,
+}
+
+impl Overlap {
+    pub fn new(fft_size: usize, hop: usize) -> Overlap {
+        Overlap { fft_size, hop, pending: Vec::with_capacity(fft_size * 4) }
+    }
+
+    /// Append `iq` to the stream and call `frame` for every complete frame
+    pub fn push<F: FnMut(&[f64])>(&mut self, iq: &[f64], mut frame: F) {
+        self.pending.extend_from_slice(iq);
+        let mut start = 0;
+        while start + self.fft_size * 2 <= self.pending.len() {
+            frame(&self.pending[start..start + self.fft_size * 2]);
+            start += self.hop * 2;
+        }
+        self.pending.drain(..start.min(self.pending.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_overlap_across_pushes() {
+        let mut overlap = Overlap::new(4, 2);
+        let mut frames = vec![];
+        let stream = (0..20).map(|x| x as f64).collect::<Vec<_>>();
+        overlap.push(&stream[..6], |f| frames.push(f.to_vec()));
+        overlap.push(&stream[6..], |f| frames.push(f.to_vec()));
+        // 10 samples, frames of 4 starting every 2 samples
+        assert_eq!(4, frames.len());
+        assert_eq!((0..8).map(|x| x as f64).collect::<Vec<_>>(), frames[0]);
+        assert_eq!((4..12).map(|x| x as f64).collect::<Vec<_>>(), frames[1]);
+        assert_eq!((12..20).map(|x| x as f64).collect::<Vec<_>>(), frames[3]);
+    }
+
+    #[test]
+    fn hop_is_limited() {
+        let settings = RealtimeSettings { overlap: 1.0, ..RealtimeSettings::default() };
+        assert!(settings.hop() >= 1);
+        assert_eq!(2048, RealtimeSettings::default().hop());
+    }
+}
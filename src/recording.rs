@@ -0,0 +1,266 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+use crate::sigmf::{self, Annotation, Capture, Global};
+use crate::rules;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    /// `.cu8` with metadata in a `.json` sidecar
+    Cu8,
+    /// `.sigmf-data` and `.sigmf-meta` pair, unsigned or signed samples
+    SigmfCu8,
+    SigmfCi8,
+}
+
+/// Raw IQ recording to `<path>_<serial>_<time ms>_<sequence>.<extension>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingSettings {
+    /// Path and file name prefix
+    pub path: String,
+    pub format: RecordingFormat,
+    /// Record continuously at this frequency, Hz. None to record buffers of sweep steps.
+    pub freq: Option<u32>,
+    /// Stop recording after this many bytes, 0 for no limit
+    pub max_bytes: u64,
+    /// Stop recording after this many seconds, 0 for no limit
+    pub max_seconds: f64,
+}
+
+/// Contents of the sidecar
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Interleaved unsigned 8 bit I and Q, 127.5 is zero
+    pub datatype: String,
+    pub samplerate: usize,
+    /// Frequency of continuous recording, Hz
+    pub center_freq: Option<u32>,
+    /// Tuner gain, 10th of dB. None for automatic gain.
+    pub gain: Option<i32>,
+    pub ppm: i32,
+    pub serial: String,
+    /// Seconds since Unix epoch
+    pub start_time: f64,
+    pub bytes: u64,
+    /// True if recording was cut by size or time limit
+    pub truncated: bool,
+    /// Sweep recordings consist of a buffer per step
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Frequency the device was tuned to, Hz
+    pub freq: u32,
+    /// Position in the data file, bytes
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Metadata {
+    pub fn new(samplerate: usize, center_freq: Option<u32>, gain: Option<i32>, ppm: i32, serial: &str) -> Metadata {
+        Metadata {
+            datatype: "cu8".to_string(),
+            samplerate,
+            center_freq,
+            gain,
+            ppm,
+            serial: serial.to_string(),
+            start_time: rules::now(),
+            bytes: 0,
+            truncated: false,
+            steps: vec![],
+        }
+    }
+}
+
+pub struct Recorder {
+    format: RecordingFormat,
+    data: BufWriter<File>,
+    data_path: String,
+    /// Path without extension
+    base: String,
+    meta: Metadata,
+    /// Captures and annotations of SigMF recording
+    sigmf: sigmf::Meta,
+    max_bytes: u64,
+    max_seconds: f64,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(settings: &RecordingSettings, meta: Metadata) -> Result<Recorder, io::Error> {
+        let serial = meta.serial.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect::<String>();
+        // Several devices may start recording in the same millisecond
+        let base = format!("{}_{}_{}", settings.path, serial, rules::file_stamp(meta.start_time));
+        let (extension, datatype) = match settings.format {
+            RecordingFormat::Cu8 => ("cu8", "cu8"),
+            RecordingFormat::SigmfCu8 => (sigmf::DATA_EXTENSION, "cu8"),
+            RecordingFormat::SigmfCi8 => (sigmf::DATA_EXTENSION, "ci8"),
+        };
+        let data_path = format!("{}.{}", base, extension);
+        let mut global = Global::new(datatype, meta.samplerate as f64);
+        global.hw = Some(format!("RTL-SDR serial {}", meta.serial));
+        global.description = Some(format!("gain {}, ppm {}",
+            meta.gain.map(|g| format!("{} dB", g as f64 / 10.0)).unwrap_or_else(|| "auto".to_string()), meta.ppm));
+        let captures = match meta.center_freq {
+            Some(freq) => vec![Capture { sample_start: 0, frequency: freq as f64, datetime: Some(sigmf::iso8601(meta.start_time)) }],
+            None => vec![],
+        };
+        Ok(Recorder {
+            format: settings.format,
+            data: BufWriter::new(File::create(&data_path)?),
+            data_path,
+            base,
+            sigmf: sigmf::Meta { global, captures, annotations: vec![] },
+            meta,
+            max_bytes: settings.max_bytes,
+            max_seconds: settings.max_seconds,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.data_path
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.meta.bytes
+    }
+
+    pub fn seconds(&self) -> f64 {
+        let t = self.started.elapsed();
+        t.as_secs() as f64 + t.subsec_nanos() as f64 / 1e9
+    }
+
+    /// Append `buffer` captured at `freq`. Returns false when a limit is reached and nothing more
+    /// will be written.
+    pub fn write(&mut self, freq: u32, buffer: &[u8]) -> Result<bool, io::Error> {
+        if self.meta.truncated {
+            return Ok(false);
+        }
+        let mut len = buffer.len() as u64;
+        if self.max_bytes > 0 && self.meta.bytes + len > self.max_bytes {
+            // Keep I/Q pairs whole
+            len = (self.max_bytes - self.meta.bytes) & !1;
+            self.meta.truncated = true;
+        }
+        if self.max_seconds > 0.0 && self.seconds() >= self.max_seconds {
+            len = 0;
+            self.meta.truncated = true;
+        }
+        if len > 0 {
+            if self.format == RecordingFormat::SigmfCi8 {
+                let mut signed = buffer[..len as usize].to_vec();
+                sigmf::to_signed(&mut signed);
+                self.data.write_all(&signed)?;
+            } else {
+                self.data.write_all(&buffer[..len as usize])?;
+            }
+            if self.meta.center_freq.is_none() {
+                self.meta.steps.push(Step { freq, offset: self.meta.bytes, len });
+                self.sigmf.captures.push(Capture {
+                    sample_start: self.meta.bytes / 2,
+                    frequency: freq as f64,
+                    datetime: Some(sigmf::iso8601(crate::rules::now())),
+                });
+            }
+            self.meta.bytes += len;
+        }
+        Ok(!self.meta.truncated)
+    }
+
+    /// Annotate a signal in the last `samples` written samples, SigMF only
+    pub fn annotate(&mut self, samples: u64, freq_lower_edge: f64, freq_upper_edge: f64, label: String) {
+        let written = self.meta.bytes / 2;
+        let samples = samples.min(written);
+        self.sigmf.annotate(Annotation { sample_start: written - samples, sample_count: samples, freq_lower_edge, freq_upper_edge, label });
+    }
+
+    /// Flush data and write the sidecar
+    pub fn finish(mut self) -> Result<Metadata, io::Error> {
+        self.data.flush()?;
+        match self.format {
+            RecordingFormat::Cu8 => {
+                let file = File::create(format!("{}.json", self.base))?;
+                serde_json::to_writer_pretty(file, &self.meta).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            },
+            RecordingFormat::SigmfCu8 | RecordingFormat::SigmfCi8 => sigmf::write_meta(&self.base, &self.sigmf)?,
+        }
+        Ok(self.meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn settings(name: &str, max_bytes: u64) -> RecordingSettings {
+        let path = std::env::temp_dir().join(name).to_str().unwrap().to_string();
+        RecordingSettings { path, format: RecordingFormat::Cu8, freq: None, max_bytes, max_seconds: 0.0 }
+    }
+
+    #[test]
+    fn size_limit_truncates() {
+        let mut recorder = Recorder::create(&settings("rtl-scanner-limit", 1001),
+                                            Metadata::new(2_000_000, None, None, 0, "0001")).unwrap();
+        assert!(recorder.write(100_000_000, &[127; 600]).unwrap());
+        assert!(!recorder.write(101_000_000, &[127; 600]).unwrap());
+        assert!(!recorder.write(102_000_000, &[127; 600]).unwrap());
+        let path = recorder.path().to_string();
+        let meta = recorder.finish().unwrap();
+        assert_eq!(1000, meta.bytes);
+        assert!(meta.truncated);
+        assert_eq!(vec![Step { freq: 100_000_000, offset: 0, len: 600 }, Step { freq: 101_000_000, offset: 600, len: 400 }],
+                   meta.steps);
+        assert_eq!(1000, fs::metadata(&path).unwrap().len());
+
+        let sidecar = path.replace(".cu8", ".json");
+        let loaded: Metadata = serde_json::from_reader(File::open(&sidecar).unwrap()).unwrap();
+        assert_eq!(meta, loaded);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(sidecar).unwrap();
+    }
+
+    #[test]
+    fn time_limit_stops_writing() {
+        let settings = RecordingSettings { max_seconds: 1e-9, ..settings("rtl-scanner-time", 0) };
+        let mut recorder = Recorder::create(&settings, Metadata::new(2_000_000, Some(100_000_000), None, 0, "0001")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(!recorder.write(100_000_000, &[127; 600]).unwrap());
+        let path = recorder.path().to_string();
+        let meta = recorder.finish().unwrap();
+        assert_eq!(0, meta.bytes);
+        assert!(meta.truncated);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.replace(".cu8", ".json")).unwrap();
+    }
+
+    #[test]
+    fn sigmf_roundtrip() {
+        let mut settings = settings("rtl-scanner-sigmf", 0);
+        settings.format = RecordingFormat::SigmfCi8;
+        let mut recorder = Recorder::create(&settings, Metadata::new(2_000_000, None, Some(300), 0, "0001")).unwrap();
+        recorder.write(100_000_000, &[0, 128, 255, 127]).unwrap();
+        recorder.annotate(2, 100.1e6, 100.2e6, "peak".to_string());
+        recorder.write(101_000_000, &[1, 2]).unwrap();
+        let path = recorder.path().to_string();
+        recorder.finish().unwrap();
+
+        let mut reader = sigmf::Reader::open(&path).unwrap();
+        assert_eq!(3, reader.total);
+        assert_eq!(vec![0, 128, 255, 127], reader.read(0, 2).unwrap());
+        assert_eq!(vec![255, 127, 1, 2], reader.read(1, 10).unwrap());
+        let meta = reader.meta;
+        assert_eq!("ci8", meta.global.datatype);
+        assert_eq!(2, meta.captures.len());
+        assert_eq!(2, meta.captures[1].sample_start);
+        assert_eq!(101e6, meta.captures[1].frequency);
+        assert_eq!(1, meta.annotations.len());
+        assert_eq!((0, 2), (meta.annotations[0].sample_start, meta.annotations[0].sample_count));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.replace(sigmf::DATA_EXTENSION, sigmf::META_EXTENSION)).unwrap();
+    }
+}
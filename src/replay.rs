@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::archive::{self, Header, Row, Statistics};
+
+/// Saved sweeps opened for viewing or replay. Archives are read on demand, CSV and Octave files
+/// are loaded into memory.
+pub enum Replay {
+    Archive(archive::Reader),
+    Loaded { header: Header, rows: Vec<Row> },
+}
+
+impl Replay {
+    /// Format is chosen by extension:
+    /// * `.rtla` archive
+    /// * `.csv` as written by `archive::to_csv`: time, then a column per bin with frequencies in
+    ///   the header line
+    /// * anything else is Octave text (`save -text`) with `freq` vector, `psd` matrix of a sweep
+    ///   per row and optional `time` vector
+    pub fn open(path: &str) -> Result<Replay, io::Error> {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        if extension == archive::EXTENSION {
+            return Ok(Replay::Archive(archive::Reader::open(path)?));
+        }
+        let text = fs::read_to_string(path)?;
+        let (freqs, rows) = if extension == "csv" { parse_csv(&text)? } else { parse_octave(&text)? };
+        Ok(Replay::Loaded { header: grid(&freqs)?, rows })
+    }
+
+    pub fn header(&self) -> &Header {
+        match self {
+            Replay::Archive(reader) => &reader.header,
+            Replay::Loaded { header, .. } => header,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        match self {
+            Replay::Archive(reader) => reader.rows(),
+            Replay::Loaded { rows, .. } => rows.len(),
+        }
+    }
+
+    pub fn time_span(&self) -> Option<(f64, f64)> {
+        match self {
+            Replay::Archive(reader) => reader.time_span(),
+            Replay::Loaded { rows, .. } => Some((rows.first()?.time, rows.last()?.time)),
+        }
+    }
+
+    /// Up to `n` last rows with time not after `until`, oldest first
+    pub fn read_last(&mut self, n: usize, until: f64) -> Result<Vec<Row>, io::Error> {
+        match self {
+            Replay::Archive(reader) => reader.read_last(n, until),
+            Replay::Loaded { rows, .. } => {
+                let end = rows.iter().rposition(|r| r.time <= until).map_or(0, |i| i + 1);
+                Ok(rows[end.saturating_sub(n)..end].to_vec())
+            },
+        }
+    }
+
+    /// Visit rows in time order while `f` returns true
+    pub fn for_each(&mut self, mut f: impl FnMut(&Row) -> bool) -> Result<(), io::Error> {
+        match self {
+            Replay::Archive(reader) => {
+                for chunk in 0..reader.index.len() {
+                    if !reader.read_chunk(chunk)?.iter().all(&mut f) {
+                        break;
+                    }
+                }
+            },
+            Replay::Loaded { rows, .. } => {
+                rows.iter().all(f);
+            },
+        }
+        Ok(())
+    }
+
+    pub fn statistics(&mut self) -> Result<Statistics, io::Error> {
+        let mut statistics = Statistics::new(self.header().bins);
+        self.for_each(|row| { statistics.add(row); true })?;
+        Ok(statistics)
+    }
+}
+
+/// Rows are expected to be evenly spaced in frequency
+fn grid(freqs: &[f64]) -> Result<Header, io::Error> {
+    if freqs.len() < 2 || freqs[1] <= freqs[0] {
+        return Err(invalid("need at least two increasing frequencies"));
+    }
+    let bin_width = (freqs[freqs.len() - 1] - freqs[0]) / (freqs.len() - 1) as f64;
+    let mut header = Header::new(freqs[0], freqs[0] + freqs.len() as f64 * bin_width, bin_width);
+    header.bins = freqs.len();
+    Ok(header)
+}
+
+/// Octave writes -Inf and NaN, CSV has empty cells for bins which were not swept
+fn parse_level(s: &str) -> Result<f64, io::Error> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(std::f64::NEG_INFINITY);
+    }
+    let level = s.to_lowercase().parse::<f64>().map_err(|_| invalid(&format!("bad number '{}'", s)))?;
+    Ok(if level.is_nan() { std::f64::NEG_INFINITY } else { level })
+}
+
+/// Frequencies from the header line and a row per line
+pub fn parse_csv(text: &str) -> Result<(Vec<f64>, Vec<Row>), io::Error> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let freqs = lines.next().ok_or_else(|| invalid("empty file"))?.split(',').skip(1).
+        map(parse_level).collect::<Result<Vec<_>, _>>()?;
+    let mut rows = vec![];
+    for line in lines {
+        let mut cells = line.split(',');
+        let time = parse_level(cells.next().unwrap_or(""))?;
+        let mut levels = cells.map(parse_level).collect::<Result<Vec<_>, _>>()?;
+        levels.resize(freqs.len(), std::f64::NEG_INFINITY);
+        rows.push(Row { time, levels });
+    }
+    Ok((freqs, rows))
+}
+
+/// Matrices of Octave text format, by name: (rows, columns, values in row order)
+fn octave_matrices(text: &str) -> Result<Vec<(String, usize, usize, Vec<f64>)>, io::Error> {
+    let mut matrices = vec![];
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        let name = match field(line, "# name:") {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let (mut rows, mut columns) = (1, 1);
+        while let Some(line) = lines.peek() {
+            let line = line.trim();
+            if !line.starts_with('#') {
+                break;
+            }
+            if let Some(n) = field(line, "# rows:") {
+                rows = n.parse().map_err(|_| invalid("bad row count"))?;
+            } else if let Some(n) = field(line, "# columns:") {
+                columns = n.parse().map_err(|_| invalid("bad column count"))?;
+            } else if line.starts_with("# name:") {
+                break;
+            }
+            lines.next();
+        }
+        let mut values = Vec::with_capacity(rows * columns);
+        while values.len() < rows * columns {
+            let line = lines.next().ok_or_else(|| invalid(&format!("'{}' is truncated", name)))?;
+            for value in line.split_whitespace() {
+                values.push(parse_level(value)?);
+            }
+        }
+        matrices.push((name, rows, columns, values));
+    }
+    Ok(matrices)
+}
+
+/// Value of `# key: value` line
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let line = line.trim();
+    if line.starts_with(key) {
+        Some(line[key.len()..].trim())
+    } else {
+        None
+    }
+}
+
+pub fn parse_octave(text: &str) -> Result<(Vec<f64>, Vec<Row>), io::Error> {
+    let matrices = octave_matrices(text)?;
+    let find = |name: &str| matrices.iter().find(|m| m.0 == name);
+    let freqs = find("freq").ok_or_else(|| invalid("no 'freq' variable"))?.3.clone();
+    let (_, rows, columns, psd) = find("psd").ok_or_else(|| invalid("no 'psd' variable"))?;
+    if *columns != freqs.len() {
+        return Err(invalid("'psd' columns do not match 'freq'"));
+    }
+    let times = find("time").map(|m| m.3.clone()).unwrap_or_default();
+    Ok((freqs, (0..*rows).map(|r| Row {
+        time: times.get(r).cloned().unwrap_or(r as f64),
+        levels: psd[r * columns..(r + 1) * columns].to_vec(),
+    }).collect()))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let (freqs, rows) = parse_csv("time,100,110,120\n5.0,-80.00,,-70.5\n6.0,-81,-60\n").unwrap();
+        assert_eq!(vec![100.0, 110.0, 120.0], freqs);
+        assert_eq!(2, rows.len());
+        assert_eq!(vec![-80.0, std::f64::NEG_INFINITY, -70.5], rows[0].levels);
+        assert_eq!((6.0, std::f64::NEG_INFINITY), (rows[1].time, rows[1].levels[2]));
+        let header = grid(&freqs).unwrap();
+        assert_eq!((100.0, 10.0, 3), (header.from, header.bin_width, header.bins));
+    }
+
+    #[test]
+    fn octave() {
+        let text = "# Created by Octave\n# name: freq\n# type: matrix\n# rows: 1\n# columns: 3\n 100 110 120\n\n\
+                    # name: psd\n# type: matrix\n# rows: 2\n# columns: 3\n -80 -Inf -70\n -81 -60 NaN\n";
+        let (freqs, rows) = parse_octave(text).unwrap();
+        assert_eq!(vec![100.0, 110.0, 120.0], freqs);
+        assert_eq!(vec![-80.0, std::f64::NEG_INFINITY, -70.0], rows[0].levels);
+        assert_eq!((1.0, std::f64::NEG_INFINITY), (rows[1].time, rows[1].levels[2]));
+        assert!(parse_octave("# name: freq\n# rows: 1\n# columns: 2\n 1 2\n").is_err());
+    }
+
+    #[test]
+    fn scrubbing_loaded_rows() {
+        let rows = (0..5).map(|i| Row { time: i as f64, levels: vec![i as f64] }).collect();
+        let mut replay = Replay::Loaded { header: grid(&[0.0, 1.0]).unwrap(), rows };
+        assert_eq!(Some((0.0, 4.0)), replay.time_span());
+        let last = replay.read_last(2, 2.5).unwrap();
+        assert_eq!(vec![1.0, 2.0], last.iter().map(|r| r.time).collect::<Vec<_>>());
+        assert!(replay.read_last(2, -1.0).unwrap().is_empty());
+    }
+}
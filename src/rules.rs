@@ -0,0 +1,222 @@
+use serde::{Serialize, Deserialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Events are appended to this file in the alert directory as json lines
+pub const EVENTS_FILE: &str = "events.jsonl";
+
+/// Tells apart files named within the same millisecond
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Alert when power in [from, to) exceeds threshold for several sweeps in a row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    pub name: String,
+    pub enabled: bool,
+    /// Hz
+    pub from: u32,
+    /// Hz
+    pub to: u32,
+    /// Peak power in the range which triggers the alert, dB
+    pub threshold_db: f64,
+    /// Consecutive sweeps above threshold to trigger, and below threshold - hysteresis to clear
+    pub sweeps: usize,
+    /// Alert clears only when power falls this much below threshold, dB
+    pub hysteresis_db: f64,
+    pub actions: Actions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Actions {
+    pub log: bool,
+    pub highlight: bool,
+    /// Shell command run on trigger, empty for none. RULE_NAME, RULE_POWER, RULE_FROM and RULE_TO
+    /// are passed in environment.
+    pub command: String,
+    /// Append the event to `EVENTS_FILE`
+    pub record: bool,
+    /// Record raw IQ of the range right after the trigger
+    pub save_iq: bool,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            name: "New rule".to_string(),
+            enabled: true,
+            from: 433_900_000,
+            to: 433_950_000,
+            threshold_db: -60.0,
+            sweeps: 2,
+            hysteresis_db: 3.0,
+            actions: Actions::default(),
+        }
+    }
+}
+
+impl Default for Actions {
+    fn default() -> Self {
+        Actions { log: true, highlight: true, command: String::new(), record: false, save_iq: false }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Triggered,
+    Cleared,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RuleState {
+    active: bool,
+    /// Consecutive sweeps towards the opposite state
+    count: usize,
+}
+
+/// Keeps state of rules between sweeps
+#[derive(Debug)]
+pub struct Engine {
+    states: Vec<RuleState>,
+}
+
+impl Engine {
+    pub fn new(rules: &[Rule]) -> Engine {
+        Engine { states: vec![RuleState::default(); rules.len()] }
+    }
+
+    /// Feed peak power of every rule's range in a finished sweep, None if the range was not swept.
+    /// Returns rules which changed state.
+    pub fn evaluate(&mut self, rules: &[Rule], peaks: &[Option<f64>]) -> Vec<(usize, Transition)> {
+        let mut transitions = vec![];
+        for (i, (rule, state)) in rules.iter().zip(self.states.iter_mut()).enumerate() {
+            let peak = match peaks.get(i) {
+                Some(Some(peak)) if rule.enabled => *peak,
+                _ => continue,
+            };
+            let towards_change = if state.active {
+                peak < rule.threshold_db - rule.hysteresis_db
+            } else {
+                peak > rule.threshold_db
+            };
+            if !towards_change {
+                state.count = 0;
+                continue;
+            }
+            state.count += 1;
+            if state.count >= rule.sweeps.max(1) {
+                state.active = !state.active;
+                state.count = 0;
+                transitions.push((i, if state.active { Transition::Triggered } else { Transition::Cleared }));
+            }
+        }
+        transitions
+    }
+}
+
+/// Highest finite bin of `psd` (first bin at `freq`) whose center falls into [from, to)
+pub fn peak(freq: f64, bin_width: f64, psd: &[f64], from: u32, to: u32) -> Option<f64> {
+    psd.iter().enumerate().
+        filter(|(k, _)| {
+            let f = freq + (*k as f64 + 0.5) * bin_width;
+            f >= from as f64 && f < to as f64
+        }).
+        map(|(_, p)| *p).
+        filter(|p| p.is_finite()).
+        max_by(crate::cmp_f64)
+}
+
+/// Record of a rule's transition written into `EVENTS_FILE`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Seconds since Unix epoch
+    pub time: f64,
+    pub rule: String,
+    pub triggered: bool,
+    pub from: u32,
+    pub to: u32,
+    pub power: f64,
+}
+
+pub fn now() -> f64 {
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    t.as_secs() as f64 + t.subsec_nanos() as f64 / 1e9
+}
+
+/// `<time ms>_<sequence>` for names of files written at `time`, unique within the process
+pub fn file_stamp(time: f64) -> String {
+    format!("{}_{}", (time * 1000.0) as u64, SEQUENCE.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn record(dir: &Path, event: &Event) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(dir.join(EVENTS_FILE))?;
+    serde_json::to_writer(&mut file, event).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    file.write_all(b"\n")
+}
+
+/// Start rule's command without waiting for it. The child is reaped by a waiter thread.
+pub fn run_command(rule: &Rule, power: f64) -> Result<(), io::Error> {
+    let mut child = Command::new("sh").
+        arg("-c").
+        arg(&rule.actions.command).
+        env("RULE_NAME", &rule.name).
+        env("RULE_POWER", format!("{:.1}", power)).
+        env("RULE_FROM", rule.from.to_string()).
+        env("RULE_TO", rule.to.to_string()).
+        spawn()?;
+    thread::spawn(move || {
+        if let Err(err) = child.wait() {
+            debug!("Failed to wait for rule command: {}", err);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers_after_consecutive_sweeps() {
+        let rules = vec![Rule::default()];
+        let mut engine = Engine::new(&rules);
+        assert!(engine.evaluate(&rules, &[Some(-50.0)]).is_empty());
+        // Dip resets the count
+        assert!(engine.evaluate(&rules, &[Some(-70.0)]).is_empty());
+        assert!(engine.evaluate(&rules, &[Some(-50.0)]).is_empty());
+        assert_eq!(vec![(0, Transition::Triggered)], engine.evaluate(&rules, &[Some(-50.0)]));
+        assert!(engine.evaluate(&rules, &[Some(-50.0)]).is_empty());
+    }
+
+    #[test]
+    fn hysteresis_prevents_flapping() {
+        let rules = vec![Rule { sweeps: 1, ..Rule::default() }];
+        let mut engine = Engine::new(&rules);
+        assert_eq!(vec![(0, Transition::Triggered)], engine.evaluate(&rules, &[Some(-59.0)]));
+        // Below threshold but within hysteresis
+        assert!(engine.evaluate(&rules, &[Some(-61.0)]).is_empty());
+        assert!(engine.evaluate(&rules, &[None]).is_empty());
+        assert_eq!(vec![(0, Transition::Cleared)], engine.evaluate(&rules, &[Some(-64.0)]));
+    }
+
+    #[test]
+    fn file_stamps_are_unique() {
+        let time = now();
+        assert_ne!(file_stamp(time), file_stamp(time));
+        assert!(file_stamp(1.5).starts_with("1500_"));
+    }
+
+    #[test]
+    fn peak_in_range() {
+        let psd = [-90.0, -40.0, std::f64::NEG_INFINITY, -70.0, -30.0];
+        assert_eq!(Some(-70.0), peak(0.0, 10.0, &psd, 20, 40));
+        assert_eq!(Some(-40.0), peak(0.0, 10.0, &psd, 0, 30));
+        assert_eq!(None, peak(0.0, 10.0, &psd, 100, 200));
+    }
+}
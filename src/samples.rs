@@ -22,32 +22,186 @@ impl Samples {
     }
 }
 
+/// Sweep stored on a frequency grid, so that data arriving in any order and traces of different
+/// ranges line up. The grid is set by the first data inserted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Spectrum {
+    /// Frequency of the first bin, Hz
+    pub from: f64,
+    pub bin_width: f64,
+    /// dB, -inf where nothing was received
+    pub levels: Vec<f64>,
+}
+
+impl Spectrum {
+    pub fn new() -> Spectrum {
+        Spectrum { from: 0.0, bin_width: 0.0, levels: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn to(&self) -> f64 {
+        self.from + self.levels.len() as f64 * self.bin_width
+    }
+
+    pub fn freq(&self, bin: usize) -> f64 {
+        self.from + bin as f64 * self.bin_width
+    }
+
+    /// Bin containing `freq`
+    pub fn bin(&self, freq: f64) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let bin = ((freq - self.from) / self.bin_width).floor();
+        if bin >= 0.0 && (bin as usize) < self.levels.len() { Some(bin as usize) } else { None }
+    }
+
+    /// Level of the bin containing `freq`, -inf outside of the spectrum
+    pub fn level_at(&self, freq: f64) -> f64 {
+        self.bin(freq).map_or(std::f64::NEG_INFINITY, |bin| self.levels[bin])
+    }
+
+    /// Store `psd` whose first bin is at `freq`, extending the spectrum as needed. Bins of
+    /// different width are matched by their centers, several falling into one bin keep the
+    /// strongest. New data replaces what was stored at the same frequencies.
+    pub fn insert(&mut self, freq: f64, bin_width: f64, psd: &[f64]) {
+        if psd.is_empty() || bin_width <= 0.0 {
+            return;
+        }
+        if self.is_empty() {
+            self.from = freq;
+            self.bin_width = bin_width;
+        }
+        // Bins relative to the grid before extending it to the left
+        let (origin, width) = (self.from, self.bin_width);
+        let target = |k: usize| ((freq + (k as f64 + 0.5) * bin_width - origin) / width).floor() as i64;
+        let (first, last) = (target(0), target(psd.len() - 1));
+        if first < 0 {
+            // Extend to the left, keeping the grid
+            let mut levels = vec![std::f64::NEG_INFINITY; (-first) as usize];
+            levels.append(&mut self.levels);
+            self.levels = levels;
+            self.from += first as f64 * self.bin_width;
+        }
+        let shift = first.min(0);
+        let end = (last - shift + 1) as usize;
+        if end > self.levels.len() {
+            self.levels.resize(end, std::f64::NEG_INFINITY);
+        }
+        for bin in (first - shift)..=(last - shift) {
+            self.levels[bin as usize] = std::f64::NEG_INFINITY;
+        }
+        for (k, &p) in psd.iter().enumerate() {
+            let level = &mut self.levels[(target(k) - shift) as usize];
+            if p > *level {
+                *level = p;
+            }
+        }
+    }
+
+    /// Frequency of every bin
+    pub fn freqs(&self) -> Vec<f64> {
+        (0..self.levels.len()).map(|bin| self.freq(bin)).collect()
+    }
+
+    /// Change of every bin relative to `reference`, dB. -inf where either has no data.
+    pub fn difference(&self, reference: &Spectrum) -> Vec<f64> {
+        self.levels.iter().enumerate().map(|(bin, &level)| {
+            let base = reference.level_at(self.freq(bin) + self.bin_width / 2.0);
+            if level.is_finite() && base.is_finite() { level - base } else { std::f64::NEG_INFINITY }
+        }).collect()
+    }
+
+    /// Ranges which rose more than `threshold_db` above `reference`: (from, to, largest rise)
+    pub fn rises(&self, reference: &Spectrum, threshold_db: f64) -> Vec<(f64, f64, f64)> {
+        let mut rises: Vec<(f64, f64, f64)> = vec![];
+        let mut in_rise = false;
+        for (bin, delta) in self.difference(reference).into_iter().enumerate() {
+            let above = delta > threshold_db;
+            let lower = self.freq(bin);
+            match rises.last_mut() {
+                Some(last) if above && in_rise => {
+                    last.1 = lower + self.bin_width;
+                    last.2 = last.2.max(delta);
+                },
+                _ if above => rises.push((lower, lower + self.bin_width, delta)),
+                _ => {},
+            }
+            in_rise = above;
+        }
+        rises
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    const INF: f64 = std::f64::NEG_INFINITY;
+
+    fn spectrum() -> Spectrum {
+        let mut s = Spectrum::new();
+        s.insert(100.0, 10.0, &[-1.0, -2.0, -3.0]);
+        s
+    }
+
     #[test]
     fn can_append() {
-        //let s = Spectrum::new(10);
+        let mut s = spectrum();
+        s.insert(130.0, 10.0, &[-4.0, -5.0]);
+        assert_eq!((100.0, 150.0), (s.from, s.to()));
+        assert_eq!(vec![-1.0, -2.0, -3.0, -4.0, -5.0], s.levels);
+        // Gap is left empty
+        s.insert(170.0, 10.0, &[-7.0]);
+        assert_eq!(vec![-1.0, -2.0, -3.0, -4.0, -5.0, INF, INF, -7.0], s.levels);
     }
 
     #[test]
     fn can_left_join() {
-
+        let mut s = spectrum();
+        s.insert(70.0, 10.0, &[-7.0, -8.0]);
+        assert_eq!((70.0, 130.0), (s.from, s.to()));
+        assert_eq!(vec![-7.0, -8.0, INF, -1.0, -2.0, -3.0], s.levels);
     }
 
     #[test]
     fn can_merge() {
-
+        let mut s = spectrum();
+        // Finer bins are reduced to the grid keeping the strongest, coarser ones fill every bin
+        // their center falls into
+        s.insert(110.0, 5.0, &[-9.0, -8.0]);
+        assert_eq!(vec![-1.0, -8.0, -3.0], s.levels);
+        s.insert(100.0, 30.0, &[-6.0]);
+        assert_eq!(vec![-1.0, -6.0, -3.0], s.levels);
+        assert_eq!(-6.0, s.level_at(115.0));
+        assert_eq!(INF, s.level_at(130.0));
     }
 
     #[test]
     fn can_left_interlap() {
-
+        let mut s = spectrum();
+        s.insert(90.0, 10.0, &[-9.0, -8.0]);
+        assert_eq!((90.0, 130.0), (s.from, s.to()));
+        assert_eq!(vec![-9.0, -8.0, -2.0, -3.0], s.levels);
     }
 
     #[test]
     fn can_right_interlap() {
+        let mut s = spectrum();
+        s.insert(120.0, 10.0, &[-9.0, -8.0]);
+        assert_eq!((100.0, 140.0), (s.from, s.to()));
+        assert_eq!(vec![-1.0, -2.0, -9.0, -8.0], s.levels);
+    }
 
+    #[test]
+    fn difference_from_reference() {
+        let reference = spectrum();
+        let mut live = Spectrum::new();
+        live.insert(90.0, 10.0, &[-5.0, -1.0, 10.0, 12.0]);
+        assert_eq!(vec![INF, 0.0, 12.0, 15.0], live.difference(&reference));
+        assert_eq!(vec![(110.0, 130.0, 15.0)], live.rises(&reference, 10.0));
     }
 }
@@ -0,0 +1,147 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Set of frequency ranges scanned repeatedly, each with its own acquisition settings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanPlan {
+    pub ranges: Vec<PlanRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlanRange {
+    pub name: String,
+    pub enabled: bool,
+    /// Hz
+    pub from: u32,
+    /// Hz
+    pub to: u32,
+    pub dwell_ms: usize,
+    /// Resolution bandwidth, Hz. Spectrum of every step is an average of dwell/(1/rbw) FFTs.
+    pub rbw: f64,
+    /// Tuner gain, 10th of dB. None to use device's gain.
+    pub gain: Option<i32>,
+    /// How often the range should be swept, seconds
+    pub revisit_s: f64,
+    /// Higher priority ranges go first when several ranges are due
+    pub priority: u32,
+}
+
+impl Default for PlanRange {
+    fn default() -> Self {
+        PlanRange {
+            name: "New range".to_string(),
+            enabled: true,
+            from: 88e6 as u32,
+            to: 108e6 as u32,
+            dwell_ms: 16,
+            rbw: 1e3,
+            gain: None,
+            revisit_s: 10.0,
+            priority: 0,
+        }
+    }
+}
+
+impl ScanPlan {
+    pub fn load(path: &Path) -> Result<ScanPlan, io::Error> {
+        let file = File::open(path)?;
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// Decides which range of the plan to sweep next.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// Time of the last visit of every range, seconds. None if never visited.
+    last_visit: Vec<Option<f64>>,
+}
+
+impl Scheduler {
+    pub fn new(plan: &ScanPlan) -> Scheduler {
+        Scheduler { last_visit: vec![None; plan.ranges.len()] }
+    }
+
+    /// Index of the range to sweep at time `now` (seconds), or None if no range is due yet.
+    /// Among due ranges the highest priority wins, then the most overdue.
+    pub fn next(&self, plan: &ScanPlan, now: f64) -> Option<usize> {
+        plan.ranges.iter().enumerate().
+            filter(|(_, r)| r.enabled).
+            filter_map(|(i, r)| {
+                let due = self.last_visit[i].map(|t| t + r.revisit_s).unwrap_or(std::f64::NEG_INFINITY);
+                if due <= now { Some((i, r.priority, due)) } else { None }
+            }).
+            max_by(|a, b| a.1.cmp(&b.1).then(crate::cmp_f64(&b.2, &a.2))).
+            map(|(i, _, _)| i)
+    }
+
+    /// Seconds until the next range is due, None if nothing is enabled.
+    pub fn wait(&self, plan: &ScanPlan, now: f64) -> Option<f64> {
+        plan.ranges.iter().enumerate().
+            filter(|(_, r)| r.enabled).
+            map(|(i, r)| self.last_visit[i].map(|t| t + r.revisit_s - now).unwrap_or(0.0).max(0.0)).
+            min_by(crate::cmp_f64)
+    }
+
+    pub fn visited(&mut self, range: usize, now: f64) {
+        self.last_visit[range] = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan() -> ScanPlan {
+        ScanPlan { ranges: vec![
+            PlanRange { name: "airband".to_string(), revisit_s: 5.0, priority: 1, ..PlanRange::default() },
+            PlanRange { name: "2m".to_string(), revisit_s: 20.0, priority: 0, ..PlanRange::default() },
+            PlanRange { name: "disabled".to_string(), enabled: false, priority: 5, ..PlanRange::default() },
+        ]}
+    }
+
+    #[test]
+    fn priority_first() {
+        let plan = plan();
+        let mut scheduler = Scheduler::new(&plan);
+        assert_eq!(Some(0), scheduler.next(&plan, 0.0));
+        scheduler.visited(0, 0.0);
+        assert_eq!(Some(1), scheduler.next(&plan, 1.0));
+        scheduler.visited(1, 1.0);
+        assert_eq!(None, scheduler.next(&plan, 2.0));
+        assert_eq!(Some(3.0), scheduler.wait(&plan, 2.0));
+    }
+
+    #[test]
+    fn high_priority_revisited_more_often() {
+        let plan = plan();
+        let mut scheduler = Scheduler::new(&plan);
+        let mut visits = vec![0; 3];
+        let mut now = 0.0;
+        while now < 60.0 {
+            if let Some(i) = scheduler.next(&plan, now) {
+                visits[i] += 1;
+                scheduler.visited(i, now);
+            }
+            now += 1.0;
+        }
+        assert_eq!(12, visits[0]);
+        assert_eq!(3, visits[1]);
+        assert_eq!(0, visits[2]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let plan = plan();
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(plan, serde_json::from_str(&json).unwrap());
+    }
+}
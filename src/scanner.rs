@@ -1,13 +1,23 @@
 use rtlsdr::RTLSDRDevice;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, mpsc, atomic::{AtomicBool, Ordering}};
 use crate::samples;
 use crate::fftw::Plan;
 use crate::dsp;
 use crate::rtl_import::rtl_import;
-use crate::charts::rescale;
-use crate::iterators::TuplesImpl;
+use crate::correction::{CorrectionSettings, IqCorrector, interpolate_center};
+use crate::sweep::{self, SweepStrategy};
+use crate::tuner::{self, DirectSampling, TunerRange, DIRECT_SAMPLING_MAX};
+use crate::scan_plan::{ScanPlan, PlanRange, Scheduler};
+use crate::mask::{self, FreqRange, Spur};
+use crate::adaptive::{self, AdaptiveSettings, StepLevel};
+use crate::zoom::{self, ZoomSettings, ZoomPlan};
+use crate::realtime::{RealtimeSettings, Overlap};
+use crate::rules::{self, Rule, Actions, Engine, Transition, Event};
+use crate::recording::{RecordingSettings, Recorder, Metadata};
+use crate::sigmf;
+use crate::archive::{self, ArchiveSettings, DeviceCalibration};
+use crate::replay::Replay;
 use std::thread;
-use num::complex::*;
 use futures::{
     prelude::*,
     sync::mpsc::{channel, UnboundedSender, UnboundedReceiver}
@@ -16,10 +26,51 @@ use log::{error, info, debug};
 use crate::samples::Samples;
 use futures::sync::BiLock;
 use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::{fmt, error::Error, time::{Duration, Instant}};
+use rtlsdr::RTLSDRError;
 
-#[derive(Debug)]
+/// How many times to retry failed read before giving up
+const READ_RETRIES: usize = 3;
+const RETRY_DELAY_MS: u64 = 50;
+/// Longest sleep between plan ranges, so that stop is not delayed
+const PLAN_IDLE_MS: u64 = 100;
+/// Length of raw IQ saved by a triggered rule
+const ALERT_IQ_MS: usize = 500;
+/// Size of reads of continuous recording
+const RECORD_CHUNK: usize = 16 * 16384;
+/// How often continuous recording reports its progress
+const RECORD_REPORT_MS: u64 = 1000;
+/// Signals this much above the median of a step are annotated in SigMF recordings, dB
+const ANNOTATE_THRESHOLD_DB: f64 = 15.0;
+/// FFT used to find signals in continuous recordings and to replay them
+const ANNOTATE_FFT: usize = 4096;
+/// Spectra per second of replayed continuous recordings
+const REPLAY_FRAME_RATE: usize = 25;
+/// Replayed sweeps keep their original pace, but no gap between them is longer than this
+const REPLAY_MAX_GAP_MS: f64 = 500.0;
+
+/// Device taking part in a scan with its calibration
+#[derive(Debug, Clone)]
+pub struct ScanDevice {
+    /// USB serial of the device, indexes change when devices are re-plugged
+    pub serial: String,
+    /// USB index at the time of selection, tells apart devices with the same serial
+    pub index: i32,
+    pub ppm: i32,
+    /// Tuner gain, 10th of dB. None for automatic gain.
+    pub gain: Option<i32>,
+    /// Added to the power of every bin, so that different devices report the same level
+    pub level_offset: f64,
+    /// Frequencies the tuner can do, direct sampling is used below it
+    pub range: TunerRange,
+}
+
+#[derive(Debug, Clone)]
 pub struct Scanner {
-    device_index: i32,
+    devices: Vec<ScanDevice>,
     width: i32,
     height: i32,
     samples: Arc<Mutex<samples::Samples>>,
@@ -28,20 +79,239 @@ pub struct Scanner {
     samplerate: usize,
     from: u32,
     to: u32,
+    correction: CorrectionSettings,
+    strategy: SweepStrategy,
+    direct_sampling: DirectSampling,
+    /// When set, ranges of the plan are swept repeatedly instead of single from-to sweep
+    plan: Option<ScanPlan>,
+    /// Ranges which are not swept
+    exclusions: Vec<FreqRange>,
+    /// Known spurs, interpolated over in results
+    spurs: Vec<Spur>,
+    /// Revisit steps with signals after a coarse sweep
+    adaptive: Option<AdaptiveSettings>,
+    /// Acquire a single high resolution view instead of sweeping
+    zoom: Option<ZoomSettings>,
+    /// Stay at one frequency and stream spectra instead of sweeping
+    realtime: Option<RealtimeSettings>,
+    /// Evaluated after every sweep
+    rules: Vec<Rule>,
+    /// Where alert events and IQ captures are written
+    alert_dir: PathBuf,
+    /// Raw IQ recording, continuous at one frequency or of sweep steps
+    record: Option<RecordingSettings>,
+    /// Replay SigMF recording or saved sweeps instead of reading devices
+    source: Option<String>,
+    /// Append every sweep, or plan cycle, to `<prefix>_<time>.rtla` archive
+    archive: Option<ArchiveSettings>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Acquisition settings of one sweep. Plan ranges override scanner's defaults.
+#[derive(Debug, Clone, Copy)]
+struct SweepParams {
+    dwell_ms: usize,
+    /// Resolution bandwidth, Hz. None for a single FFT over the whole dwell.
+    rbw: Option<f64>,
+    /// Overrides device's gain, 10th of dB
+    gain: Option<i32>,
+    /// Revisit of an adaptive sweep, reported as `ScannerStatus::Refined`
+    refined: bool,
+}
+
+impl<'a> From<&'a PlanRange> for SweepParams {
+    fn from(range: &PlanRange) -> Self {
+        SweepParams { dwell_ms: range.dwell_ms, rbw: Some(range.rbw), gain: range.gain, refined: false }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    /// Device is unplugged or can not be opened
+    DeviceLost(String),
+    /// Driver failed to tune, frequency in Hz
+    Tune { freq: u32, cause: String },
+    /// Driver kept failing to read samples at frequency `freq`
+    Read { freq: u32, cause: String },
+    /// Driver kept returning less data than requested at frequency `freq`
+    ShortRead { freq: u32, expected: usize, got: usize },
+    /// FFTW could not make a plan of given size
+    FftPlan(usize),
+    /// Device configuration call failed
+    Driver { call: &'static str, cause: String },
+    /// Scan parameters do not make sense
+    Config(String),
+    /// Calibration could not find reference carrier at given frequency
+    NoCarrier(u32),
+    /// Serial could not be written into EEPROM
+    Eeprom(String),
+    /// Writing captured data failed
+    Io { path: String, cause: String },
+    /// Reading recording failed
+    Source { path: String, cause: String },
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::DeviceLost(serial) => write!(f, "Device {} is lost", serial),
+            ScanError::Tune { freq, cause } => write!(f, "Failed to tune to {} MHz: {}", *freq as f64 / 1e6, cause),
+            ScanError::Read { freq, cause } => write!(f, "Failed to read at {} MHz: {}", *freq as f64 / 1e6, cause),
+            ScanError::ShortRead { freq, expected, got } =>
+                write!(f, "Short read at {} MHz: expected {} bytes, got {}", *freq as f64 / 1e6, expected, got),
+            ScanError::FftPlan(size) => write!(f, "Failed to create FFT plan of size {}", size),
+            ScanError::Driver { call, cause } => write!(f, "{} failed: {}", call, cause),
+            ScanError::Config(msg) => write!(f, "{}", msg),
+            ScanError::NoCarrier(freq) => write!(f, "Reference carrier not found near {} MHz", *freq as f64 / 1e6),
+            ScanError::Eeprom(cause) => write!(f, "Failed to write serial: {}", cause),
+            ScanError::Io { path, cause } => write!(f, "Failed to write {}: {}", path, cause),
+            ScanError::Source { path, cause } => write!(f, "Failed to read {}: {}", path, cause),
+        }
+    }
+}
+
+impl Error for ScanError {}
+
+/// Wrap failure of device configuration call
+pub fn driver_error(call: &'static str) -> impl Fn(RTLSDRError) -> ScanError {
+    move |err| ScanError::Driver { call, cause: err.to_string() }
+}
+
+/// Open device by serial, the index may have changed since the device was probed. Devices which
+/// share the serial can only be told apart by `index`, which must still have that serial.
+pub fn open_device(serial: &str, index: i32) -> Result<rtlsdr::RTLSDRDevice, ScanError> {
+    let lost = || ScanError::DeviceLost(serial.to_string());
+    let matching = (0..rtlsdr::get_device_count()).
+        filter(|&i| rtlsdr::get_device_usb_strings(i).map(|usb| usb.serial == serial).unwrap_or(false)).
+        collect::<Vec<_>>();
+    let index = match matching.len() {
+        1 => matching[0],
+        n if n > 1 && matching.contains(&index) => index,
+        _ => return Err(lost()),
+    };
+    rtlsdr::open(index).map_err(|_| lost())
+}
+
+fn is_attached(serial: &str) -> bool {
+    rtlsdr::get_index_by_serial(serial.to_string()).is_ok()
+}
+
+/// Tune the device, telling lost device from tuning failure
+pub fn tune(driver: &mut rtlsdr::RTLSDRDevice, serial: &str, freq: u32) -> Result<(), ScanError> {
+    driver.set_center_freq(freq).map_err(|err| {
+        if is_attached(serial) {
+            ScanError::Tune { freq, cause: err.to_string() }
+        } else {
+            ScanError::DeviceLost(serial.to_string())
+        }
+    })
+}
+
+/// Average power of consecutive frames of plan's size in cu8 `buffer`, None if the buffer is
+/// shorter than a frame
+fn average_psd(plan: &Plan, corrector: &mut IqCorrector, buffer: &[u8]) -> Option<Vec<f64>> {
+    let n = plan.len();
+    let frames = buffer.len() / 2 / n;
+    if frames == 0 {
+        return None;
+    }
+    let input = plan.get_input();
+    let mut average = vec![0_f64; n];
+    for frame in 0..frames {
+        rtl_import(&buffer[frame * n * 2..], n * 2, input);
+        corrector.process(input);
+        plan.execute();
+        dsp::accumulate_power(&mut average, &dsp::ordered_psd(plan.get_output()));
+    }
+    let mut psd = dsp::average_power(&average, frames);
+    interpolate_center(&mut psd, corrector.settings.center_bins);
+    Some(psd)
+}
+
+/// Wrap failure to write recorded data
+fn io_error(path: &str) -> impl Fn(std::io::Error) -> ScanError {
+    let path = path.to_string();
+    move |err| ScanError::Io { path: path.clone(), cause: err.to_string() }
+}
+
+/// Wrap failure to read replayed file
+fn source_error(path: &str) -> impl Fn(std::io::Error) -> ScanError {
+    let path = path.to_string();
+    move |err| ScanError::Source { path: path.clone(), cause: err.to_string() }
+}
+
+/// Manual gain in 10th of dB, or automatic gain if None
+fn set_gain(driver: &mut rtlsdr::RTLSDRDevice, gain: Option<i32>) -> Result<(), ScanError> {
+    match gain {
+        Some(gain) => {
+            driver.set_tuner_gain_mode(true).map_err(driver_error("set_tuner_gain_mode"))?;
+            driver.set_tuner_gain(gain).map_err(driver_error("set_tuner_gain"))
+        },
+        None => driver.set_tuner_gain_mode(false).map_err(driver_error("set_tuner_gain_mode")),
+    }
+}
+
+/// Read exactly `len` bytes, retrying transient failures
+pub fn read(driver: &mut rtlsdr::RTLSDRDevice, serial: &str, freq: u32, len: usize) -> Result<Vec<u8>, ScanError> {
+    let mut last_error = None;
+    for attempt in 0..=READ_RETRIES {
+        if attempt > 0 {
+            debug!("Retrying read at {} ({}/{}): {}", freq, attempt, READ_RETRIES, last_error.as_ref().unwrap());
+            thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+            if !is_attached(serial) {
+                return Err(ScanError::DeviceLost(serial.to_string()));
+            }
+            let _ = driver.reset_buffer();
+        }
+        // TODO: add borrowed buffer override to rtlsdr driver
+        match driver.read_sync(len) {
+            Ok(ref buffer) if buffer.len() < len =>
+                last_error = Some(ScanError::ShortRead { freq, expected: len, got: buffer.len() }),
+            Ok(buffer) => return Ok(buffer),
+            Err(err) => last_error = Some(ScanError::Read { freq, cause: err.to_string() }),
+        }
+    }
+    Err(last_error.unwrap())
 }
 
 pub enum ScannerStatus {
     Info(String),
-    Error(String),
-    Data(Vec<f64>),
+    /// Errors which stop scanning are followed by `Complete`, data received so far stays valid
+    Error(ScanError),
+    /// Power spectrum density in dB, `freq` is frequency of the first bin, Hz
+    Data { freq: f64, bin_width: f64, psd: Vec<f64> },
+    /// Revisited part of the sweep with finer resolution, follows `Data` of the coarse sweep
+    Refined { freq: f64, bin_width: f64, psd: Vec<f64> },
+    /// High resolution view of the zoom span
+    Zoom { freq: f64, bin_width: f64, psd: Vec<f64> },
+    /// Spectrum of the full sample rate in real-time mode, sent at display rate
+    Live { freq: f64, bin_width: f64, psd: Vec<f64> },
+    /// Rule at `index` of scanner's rules changed state, `power` is the peak in rule's range, dB.
+    /// Name and actions are those the scan started with.
+    Alert { index: usize, name: String, actions: Actions, triggered: bool, power: f64 },
+    /// Progress of raw IQ recording
+    Recording { path: String, bytes: u64, seconds: f64 },
+    /// New sweep of [from, to) begins, Hz. Plan scans repeat this for every visited range.
+    SweepStarted { from: u32, to: u32 },
+    /// Frequency correction measured by calibration
+    Calibrated { serial: String, ppm: i32 },
     Complete,
 }
 
+/// Sweep recording of a device, shared by the sweeps of a scan. None once the limit is reached.
+type SharedRecorder = Arc<Mutex<Option<Recorder>>>;
+
+/// Messages from per-device sweep threads
+enum WorkerStatus {
+    Status(ScannerStatus),
+    Done(Result<(), ScanError>),
+}
+
 impl Scanner {
-    pub fn new(device_index: i32, samplerate: usize, from: u32, to: u32, dwell_ms: usize, bandwidth: usize) -> Scanner {
-        let device = rtlsdr::open(device_index);
+    /// Range is split between `devices`, which sweep in parallel
+    pub fn new(devices: Vec<ScanDevice>, samplerate: usize, from: u32, to: u32, dwell_ms: usize, bandwidth: usize) -> Scanner {
         Scanner {
-            device_index,
+            devices,
             height: 0,
             width: 0,
             samples: Arc::new(Mutex::new(samples::Samples::new(samplerate, from as usize, to as usize, dwell_ms, bandwidth))),
@@ -49,62 +319,778 @@ impl Scanner {
             dwell_ms,
             samplerate,
             from,
-            to
+            to,
+            correction: CorrectionSettings::default(),
+            strategy: SweepStrategy::Centered,
+            direct_sampling: DirectSampling::Off,
+            plan: None,
+            exclusions: vec![],
+            spurs: vec![],
+            adaptive: None,
+            zoom: None,
+            realtime: None,
+            rules: vec![],
+            alert_dir: PathBuf::from("."),
+            record: None,
+            source: None,
+            archive: None,
+            stop: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    pub fn correction(mut self, correction: CorrectionSettings) -> Self {
+        self.correction = correction;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: SweepStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Switch to direct sampling on given ADC branch for the part of the range below tuner range
+    pub fn direct_sampling(mut self, direct_sampling: DirectSampling) -> Self {
+        self.direct_sampling = direct_sampling;
+        self
+    }
+
+    /// Sweep ranges of the plan repeatedly, until stopped. Replaces from-to range and dwell.
+    pub fn plan(mut self, plan: ScanPlan) -> Self {
+        self.plan = Some(plan);
+        self
+    }
+
+    /// Skip `exclusions` and mask `spurs` out of results
+    pub fn mask(mut self, exclusions: Vec<FreqRange>, spurs: Vec<Spur>) -> Self {
+        self.exclusions = exclusions;
+        self.spurs = spurs;
+        self
+    }
+
+    /// Sweep with the coarse dwell, then revisit steps with signals
+    pub fn adaptive(mut self, adaptive: AdaptiveSettings) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Zoom into a span which fits into a single tuning with the first device
+    pub fn zoom(mut self, zoom: ZoomSettings) -> Self {
+        self.zoom = Some(zoom);
+        self
+    }
+
+    /// Stream spectra of one tuning with the first device until stopped
+    pub fn realtime(mut self, realtime: RealtimeSettings) -> Self {
+        self.realtime = Some(realtime);
+        self
+    }
+
+    /// Alert rules, evaluated on every finished sweep
+    pub fn rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Directory of alert events and IQ captures, the working directory by default
+    pub fn alert_dir(mut self, dir: PathBuf) -> Self {
+        self.alert_dir = dir;
+        self
+    }
+
+    /// Record raw IQ. With `freq` set the first device records continuously instead of sweeping,
+    /// otherwise every device records buffers of its sweep steps.
+    pub fn record(mut self, record: RecordingSettings) -> Self {
+        self.record = Some(record);
+        self
+    }
+
+    /// Append every finished sweep to an archive, see `archive`. Plan scans append a row once
+    /// all of their enabled ranges were swept.
+    pub fn archive(mut self, settings: ArchiveSettings) -> Self {
+        self.archive = Some(settings);
+        self
+    }
+
+    /// Replay a file, no device is used. SigMF sweep recordings are replayed as sweeps with
+    /// scanner's sweep strategy, continuous ones as real-time spectra. Other files are saved
+    /// sweeps, see `Replay::open`.
+    pub fn source(mut self, path: String) -> Self {
+        self.source = Some(path);
+        self
+    }
+
+    /// Setting this flag aborts scanning after current step
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
 
-    // TODO: handle device calls more intelligently than just unwrap(). If device is removed from usb
-    // and function call fail, it would cause panic.
     pub fn start(mut self) -> Arc<Mutex<VecDeque<ScannerStatus>>> {
         let queue = Arc::new(Mutex::new(VecDeque::new()));
         let queue2 = queue.clone();
         thread::spawn(move || {
             if let Err(err) = self.scan(&queue2) {
+                error!("Scan failed: {}", err);
                 let mut queue2 = queue2.lock().unwrap();
-                queue2.push_back(ScannerStatus::Error(err.to_string()));
+                queue2.push_back(ScannerStatus::Error(err));
+                queue2.push_back(ScannerStatus::Complete);
             }
         });
         queue
     }
 
-    fn scan(&mut self, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), rtlsdr::RTLSDRError> {
-        let mut driver = rtlsdr::open(self.device_index)?;
+    fn scan(&mut self, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        if let Some(path) = self.source.clone() {
+            if sigmf::base_path(&path) != path {
+                self.scan_file(&path, channel)?;
+            } else {
+                self.scan_sweeps(&path, channel)?;
+            }
+            let mut channel = channel.lock().unwrap();
+            channel.push_back(ScannerStatus::Info("Replay complete".to_string()));
+            channel.push_back(ScannerStatus::Complete);
+            return Ok(());
+        }
+        if self.devices.is_empty() {
+            return Err(ScanError::Config("No device selected".to_string()));
+        }
+
+        let record_at = self.record.as_ref().and_then(|r| r.freq);
+        match (self.realtime, self.zoom, self.plan.clone()) {
+            _ if record_at.is_some() => self.scan_record(record_at.unwrap(), channel)?,
+            (Some(realtime), _, _) => self.scan_realtime(realtime, channel)?,
+            (None, Some(zoom), _) => self.scan_zoom(zoom, channel)?,
+            (None, None, Some(plan)) => self.scan_plan(&plan, channel)?,
+            (None, None, None) => {
+                if self.from >= self.to {
+                    return Err(ScanError::Config(format!("Invalid range {}-{}", self.from, self.to)));
+                }
+                channel.lock().unwrap().push_back(ScannerStatus::Info("scanning...".to_string()));
+                debug!("Sent 'scanning' to channel");
+                let dwell_ms = self.adaptive.map(|a| a.coarse_dwell_ms).unwrap_or(self.dwell_ms);
+                let params = SweepParams { dwell_ms, rbw: None, gain: None, refined: false };
+                // Rules trigger after several sweeps in a row, so keep sweeping while any is enabled
+                let repeat = self.rules.iter().any(|r| r.enabled);
+                let mut engine = Engine::new(&self.rules);
+                let recorders = self.sweep_recorders()?;
+                let mut archive = None;
+                loop {
+                    let mut row = None;
+                    self.sweep_devices(self.from, self.to, params, &mut engine, &recorders, &mut archive, &mut row, channel);
+                    self.archive_row(&mut archive, row, channel);
+                    if !repeat || self.stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                }
+                self.finish_recordings(recorders, channel);
+                self.finish_archive(archive, channel);
+            }
+        }
+
+        {
+            let mut channel = channel.lock().unwrap();
+            channel.push_back(ScannerStatus::Info("Scanning complete".to_string()));
+            channel.push_back(ScannerStatus::Complete);
+        }
+        Ok(())
+    }
+
+    /// Open the device and set sample rate and frequency correction
+    fn open(&self, device: &ScanDevice) -> Result<RTLSDRDevice, ScanError> {
+        let mut driver = open_device(&device.serial, device.index)?;
+        driver.set_sample_rate(self.samplerate as u32).map_err(driver_error("set_sample_rate"))?;
+        // Driver refuses to set the same correction again, and fresh device has 0
+        if device.ppm != 0 {
+            driver.set_freq_correction(device.ppm).map_err(driver_error("set_freq_correction"))?;
+        }
+        Ok(driver)
+    }
+
+    /// Record raw IQ at one frequency until stopped or a limit is reached
+    fn scan_record(&self, freq: u32, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let device = &self.devices[0];
+        let mut driver = self.open(device)?;
+        set_gain(&mut driver, device.gain)?;
+        driver.set_tuner_bandwidth(self.samplerate as u32).map_err(driver_error("set_tuner_bandwidth"))?;
+        tune(&mut driver, &device.serial, freq)?;
+        driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
 
-        channel.lock().unwrap().push_back(ScannerStatus::Info("scanning...".to_string()));
-        debug!("Sent 'scanning' to channel");
+        let annotate_plan = Plan::new(ANNOTATE_FFT).ok_or(ScanError::FftPlan(ANNOTATE_FFT))?;
+        let mut recorder = self.recorder(device, Some(freq))?;
+        channel.lock().unwrap().push_back(ScannerStatus::Info(format!(
+            "Recording {} MHz to {}", freq as f64 / 1e6, recorder.path())));
+        let res = self.record_loop(&mut driver, device, freq, &annotate_plan, &mut recorder, channel);
+        self.finish_recording(recorder, &|status: ScannerStatus| channel.lock().unwrap().push_back(status));
+        res
+    }
 
-        let step = self.bandwidth / 2;
-        let start = self.from as usize - self.bandwidth; //(self.from * 1e6) as usize - self.bandwidth;
-        let end = self.to as usize + self.bandwidth*2; //(self.to * 1e6) as usize + self.bandwidth * 2;
+    /// Write buffers at `freq` until stopped or recorder's limit is reached
+    fn record_loop(&self, driver: &mut RTLSDRDevice, device: &ScanDevice, freq: u32, annotate_plan: &Plan,
+                   recorder: &mut Recorder, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let mut corrector = IqCorrector::new(self.correction);
+        let mut reported = Instant::now();
+        while !self.stop.load(Ordering::Relaxed) {
+            let buffer = read(driver, &device.serial, freq, RECORD_CHUNK)?;
+            let more = recorder.write(freq, &buffer).map_err(io_error(recorder.path()))?;
+            if let Some(psd) = average_psd(annotate_plan, &mut corrector, &buffer) {
+                let bin_width = self.samplerate as f64 / ANNOTATE_FFT as f64;
+                let first = freq as f64 - (ANNOTATE_FFT / 2) as f64 * bin_width;
+                for (lower, upper, peak) in adaptive::peaks(first, bin_width, &psd, ANNOTATE_THRESHOLD_DB) {
+                    recorder.annotate(buffer.len() as u64 / 2, lower, upper, format!("peak {:.1} dB", peak + device.level_offset));
+                }
+            }
+            if reported.elapsed() >= Duration::from_millis(RECORD_REPORT_MS) || !more {
+                reported = Instant::now();
+                channel.lock().unwrap().push_back(ScannerStatus::Recording {
+                    path: recorder.path().to_string(), bytes: recorder.bytes(), seconds: recorder.seconds() });
+            }
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-        // TODO: align to 512
-        let sample_count = (self.dwell_ms * self.samplerate) / 1000;
-        let buffer_size = calculate_aligned_buffer_size(sample_count);
-        debug!("Buffer size {} bytes, {} samples", buffer_size, sample_count);
+    /// Replay SigMF recording
+    fn scan_file(&self, path: &str, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let mut reader = sigmf::Reader::open(path).map_err(source_error(path))?;
+        let meta = reader.meta.clone();
+        if meta.captures.is_empty() {
+            return Err(ScanError::Source { path: path.to_string(), cause: "no captures".to_string() });
+        }
+        let samplerate = meta.global.sample_rate as usize;
+        let total = reader.total;
+        let mut corrector = IqCorrector::new(self.correction);
+        let push = |status| channel.lock().unwrap().push_back(status);
+        push(ScannerStatus::Info(format!("Replaying {} captures of {}", meta.captures.len(), path)));
 
-        let fftPlan = Plan::new(sample_count as usize);
+        if meta.captures.len() == 1 {
+            // Continuous recording, replay at real-time pace
+            let fft_plan = Plan::new(ANNOTATE_FFT).ok_or(ScanError::FftPlan(ANNOTATE_FFT))?;
+            let bin_width = samplerate as f64 / ANNOTATE_FFT as f64;
+            let freq = meta.captures[0].frequency - (ANNOTATE_FFT / 2) as f64 * bin_width;
+            let chunk = (samplerate / REPLAY_FRAME_RATE).max(ANNOTATE_FFT);
+            let mut start = 0;
+            while !self.stop.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                let buffer = reader.read(start, chunk).map_err(source_error(path))?;
+                if buffer.is_empty() {
+                    break;
+                }
+                start += buffer.len() as u64 / 2;
+                if let Some(psd) = average_psd(&fft_plan, &mut corrector, &buffer) {
+                    push(ScannerStatus::Live { freq, bin_width, psd });
+                }
+                let frame = Duration::from_millis(1000 / REPLAY_FRAME_RATE as u64);
+                if let Some(wait) = frame.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+            return Ok(());
+        }
+
+        // Sweep recording, a capture per step
+        let window = self.strategy.window(samplerate, self.bandwidth).map_err(ScanError::Config)?;
+        let first = meta.captures.first().unwrap().frequency;
+        let last = meta.captures.last().unwrap().frequency;
+        push(ScannerStatus::SweepStarted { from: (first + window.low as f64) as u32, to: (last + window.high as f64) as u32 });
+        let mut plans: Vec<Plan> = vec![];
+        for (i, capture) in meta.captures.iter().enumerate() {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let (start, end) = meta.capture_samples(i, total);
+            let n = (end - start) as usize;
+            if n < 2 {
+                continue;
+            }
+            if !plans.iter().any(|p| p.len() == n) {
+                plans.push(Plan::new(n).ok_or(ScanError::FftPlan(n))?);
+            }
+            let fft_plan = plans.iter().find(|p| p.len() == n).unwrap();
+            corrector.retuned();
+            let buffer = reader.read(start, n).map_err(source_error(path))?;
+            let psd = match average_psd(fft_plan, &mut corrector, &buffer) {
+                Some(psd) => psd,
+                None => continue,
+            };
+
+            let bins = window.bins(n, samplerate);
+            let bin_width = samplerate as f64 / n as f64;
+            let freq = capture.frequency + (bins.start as f64 - (n / 2) as f64) * bin_width;
+            let mut psd = psd[bins].to_vec();
+            mask::apply(&mut psd, freq, bin_width, &self.exclusions, &self.spurs);
+            push(ScannerStatus::Data { freq, bin_width, psd });
+        }
+        Ok(())
+    }
+
+    /// Replay saved sweeps as if they were swept now
+    fn scan_sweeps(&self, path: &str, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let mut replay = Replay::open(path).map_err(source_error(path))?;
+        let header = replay.header().clone();
+        let push = |status| channel.lock().unwrap().push_back(status);
+        push(ScannerStatus::Info(format!("Replaying {} sweeps of {}", replay.rows(), path)));
+        let mut previous: Option<f64> = None;
+        replay.for_each(|row| {
+            if self.stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            if let Some(previous) = previous {
+                let gap = ((row.time - previous) * 1000.0).max(0.0).min(REPLAY_MAX_GAP_MS);
+                thread::sleep(Duration::from_millis(gap as u64));
+            }
+            previous = Some(row.time);
+            push(ScannerStatus::SweepStarted { from: header.from as u32, to: header.to() as u32 });
+            push(ScannerStatus::Data { freq: header.from, bin_width: header.bin_width, psd: row.levels.clone() });
+            true
+        }).map_err(source_error(path))
+    }
+
+    fn recorder(&self, device: &ScanDevice, freq: Option<u32>) -> Result<Recorder, ScanError> {
+        let settings = self.record.as_ref().unwrap();
+        let meta = Metadata::new(self.samplerate, freq, device.gain, device.ppm, &device.serial);
+        Recorder::create(settings, meta).map_err(io_error(&settings.path))
+    }
+
+    /// Sweep recording of every device, one for the whole scan so that limits cap all of it. Empty
+    /// when sweeps are not recorded.
+    fn sweep_recorders(&self) -> Result<Vec<SharedRecorder>, ScanError> {
+        self.devices.iter().map(|device| {
+            let recorder = match self.record {
+                Some(_) => Some(self.recorder(device, None)?),
+                None => None,
+            };
+            Ok(Arc::new(Mutex::new(recorder)))
+        }).collect()
+    }
+
+    fn finish_recordings(&self, recorders: Vec<SharedRecorder>, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) {
+        for recorder in recorders {
+            if let Some(recorder) = recorder.lock().unwrap().take() {
+                self.finish_recording(recorder, &|status: ScannerStatus| channel.lock().unwrap().push_back(status));
+            }
+        }
+    }
+
+    fn finish_recording(&self, recorder: Recorder, emit: &dyn Fn(ScannerStatus)) {
+        let path = recorder.path().to_string();
+        match recorder.finish() {
+            Ok(meta) => emit(ScannerStatus::Info(format!(
+                "Recorded {} bytes to {}{}", meta.bytes, path, if meta.truncated { ", limit reached" } else { "" }))),
+            Err(err) => emit(ScannerStatus::Error(ScanError::Io { path, cause: err.to_string() })),
+        }
+    }
+
+    /// Read continuously at one frequency, reporting average of overlapping frames at display rate
+    fn scan_realtime(&self, live: RealtimeSettings, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let device = &self.devices[0];
+        if !device.range.contains(live.freq) {
+            return Err(ScanError::Config(format!("{} MHz is outside of tuner range {}", live.freq as f64 / 1e6, device.range)));
+        }
+        let fft_size = live.fft_size.max(2);
+        let mut driver = self.open(device)?;
+        set_gain(&mut driver, device.gain)?;
+        driver.set_tuner_bandwidth(self.samplerate as u32).map_err(driver_error("set_tuner_bandwidth"))?;
+        tune(&mut driver, &device.serial, live.freq)?;
+        driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
+        channel.lock().unwrap().push_back(ScannerStatus::Info(format!(
+            "Real-time at {} MHz, RBW {:.1} Hz", live.freq as f64 / 1e6, self.samplerate as f64 / fft_size as f64)));
+
+        let fft_plan = Plan::new(fft_size).ok_or(ScanError::FftPlan(fft_size))?;
+        let input = fft_plan.get_input();
+        let output: &[f64] = fft_plan.get_output();
+        let window = dsp::hann(fft_size);
+        // Windowing takes away power which unwindowed sweeps keep, add it back so levels match
+        let window_loss = 10.0 * dsp::window_power(&window).log10();
+        let mut corrector = IqCorrector::new(self.correction);
+        let mut overlap = Overlap::new(fft_size, live.hop());
+
+        let samples = (self.samplerate as f64 / live.frame_rate.max(1.0)) as usize;
+        let buffer_size = calculate_aligned_buffer_size(samples);
+        let bin_width = self.samplerate as f64 / fft_size as f64;
+        let freq = live.freq as f64 - (fft_size / 2) as f64 * bin_width;
+        let mut iq = vec![0_f64; samples * 2];
+        while !self.stop.load(Ordering::Relaxed) {
+            // Padding read for alignment is dropped, so frames keep their spacing
+            let buffer = read(&mut driver, &device.serial, live.freq, buffer_size)?;
+            rtl_import(&buffer, samples * 2, &mut iq);
+            corrector.process(&mut iq);
+
+            let mut average = vec![0_f64; fft_size];
+            let mut frames = 0;
+            overlap.push(&iq, |frame| {
+                for (k, w) in window.iter().enumerate() {
+                    input[2 * k] = frame[2 * k] * w;
+                    input[2 * k + 1] = frame[2 * k + 1] * w;
+                }
+                fft_plan.execute();
+                dsp::accumulate_power(&mut average, &dsp::ordered_psd(output));
+                frames += 1;
+            });
+            if frames == 0 {
+                continue;
+            }
+
+            let mut psd = dsp::average_power(&average, frames);
+            interpolate_center(&mut psd, self.correction.center_bins);
+            let mut psd = psd.iter().map(|p| p - window_loss + device.level_offset).collect::<Vec<_>>();
+            mask::apply(&mut psd, freq, bin_width, &self.exclusions, &self.spurs);
+            channel.lock().unwrap().push_back(ScannerStatus::Live { freq, bin_width, psd });
+        }
+        channel.lock().unwrap().push_back(ScannerStatus::Info("Real-time stopped".to_string()));
+        Ok(())
+    }
+
+    /// Tune once, decimate to the span and average large FFTs
+    fn scan_zoom(&self, zoom: ZoomSettings, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        let device = &self.devices[0];
+        let plan = ZoomPlan::new(zoom.from, zoom.to, self.samplerate).map_err(ScanError::Config)?;
+        if !device.range.contains(plan.tuned) {
+            return Err(ScanError::Config(format!("Zoom at {} MHz is outside of tuner range {}",
+                                                 plan.tuned as f64 / 1e6, device.range)));
+        }
+        let fft_size = zoom.fft_size.max(2);
+        let averages = zoom.averages.max(1);
+        channel.lock().unwrap().push_back(ScannerStatus::Info(format!(
+            "Zooming into {}-{} MHz, decimation {}, RBW {:.1} Hz", zoom.from as f64 / 1e6, zoom.to as f64 / 1e6,
+            plan.decimation, self.samplerate as f64 / plan.decimation as f64 / fft_size as f64)));
+
+        let mut driver = self.open(device)?;
+        set_gain(&mut driver, device.gain)?;
+        driver.set_tuner_bandwidth(self.samplerate as u32).map_err(driver_error("set_tuner_bandwidth"))?;
+        tune(&mut driver, &device.serial, plan.tuned)?;
+        driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
+
+        let samples = plan.raw_samples(fft_size * averages);
+        let buffer_size = calculate_aligned_buffer_size(samples);
+        // First buffer after retuning may have samples from previous frequency
+        read(&mut driver, &device.serial, plan.tuned, buffer_size)?;
+        let buffer = read(&mut driver, &device.serial, plan.tuned, buffer_size)?;
+
+        let mut iq = vec![0_f64; samples * 2];
+        rtl_import(&buffer, samples * 2, &mut iq);
+        IqCorrector::new(self.correction).process(&mut iq);
+        let decimated = zoom::decimate(&iq, plan.shift, self.samplerate, plan.decimation);
+
+        let fft_plan = Plan::new(fft_size).ok_or(ScanError::FftPlan(fft_size))?;
+        let input = fft_plan.get_input();
+        let output: &[f64] = fft_plan.get_output();
+        let frames = (decimated.len() / 2 / fft_size).min(averages);
+        let mut average = vec![0_f64; fft_size];
+        for frame in 0..frames {
+            input.copy_from_slice(&decimated[frame * fft_size * 2..(frame + 1) * fft_size * 2]);
+            fft_plan.execute();
+            dsp::accumulate_power(&mut average, &dsp::ordered_psd(output));
+        }
+        let mut psd = dsp::average_power(&average, frames);
+        if plan.shift == 0.0 {
+            // DC spike is in the middle of the span
+            interpolate_center(&mut psd, self.correction.center_bins);
+        }
+
+        let (bins, freq, bin_width) = plan.bins(zoom.from, zoom.to, fft_size, self.samplerate);
+        let mut psd = psd[bins].iter().map(|p| p + device.level_offset).collect::<Vec<_>>();
+        mask::apply(&mut psd, freq, bin_width, &self.exclusions, &self.spurs);
+        channel.lock().unwrap().push_back(ScannerStatus::Zoom { freq, bin_width, psd });
+        Ok(())
+    }
+
+    /// Visit ranges of the plan as they become due, until stopped
+    fn scan_plan(&self, plan: &ScanPlan, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) -> Result<(), ScanError> {
+        if let Some(range) = plan.ranges.iter().find(|r| r.enabled && (r.from >= r.to || r.dwell_ms == 0 || r.rbw <= 0.0)) {
+            return Err(ScanError::Config(format!("Invalid plan range '{}'", range.name)));
+        }
+        if !plan.ranges.iter().any(|r| r.enabled) {
+            return Err(ScanError::Config("Scan plan has no enabled ranges".to_string()));
+        }
+
+        let mut scheduler = Scheduler::new(plan);
+        let mut engine = Engine::new(&self.rules);
+        let recorders = self.sweep_recorders()?;
+        let (mut archive, mut row) = (None, None);
+        // Ranges swept into the archive row of the current cycle
+        let mut visited = vec![false; plan.ranges.len()];
+        let started = Instant::now();
+        let elapsed = || { let t = started.elapsed(); t.as_secs() as f64 + t.subsec_nanos() as f64 / 1e9 };
+        while !self.stop.load(Ordering::Relaxed) {
+            let now = elapsed();
+            match scheduler.next(plan, now) {
+                Some(i) => {
+                    let range = &plan.ranges[i];
+                    channel.lock().unwrap().push_back(ScannerStatus::Info(format!("Sweeping '{}'", range.name)));
+                    scheduler.visited(i, now);
+                    self.sweep_devices(range.from, range.to, SweepParams::from(range), &mut engine, &recorders, &mut archive, &mut row, channel);
+                    visited[i] = true;
+                    if plan.ranges.iter().zip(&visited).all(|(r, v)| *v || !r.enabled) {
+                        self.archive_row(&mut archive, row.take(), channel);
+                        visited = vec![false; plan.ranges.len()];
+                    }
+                },
+                None => {
+                    let wait = scheduler.wait(plan, now).unwrap_or(0.0);
+                    thread::sleep(Duration::from_millis(((wait * 1000.0) as u64).min(PLAN_IDLE_MS).max(1)));
+                }
+            }
+        }
+        self.finish_recordings(recorders, channel);
+        // Keep the ranges of an interrupted cycle which were swept
+        self.archive_row(&mut archive, row, channel);
+        self.finish_archive(archive, channel);
+        Ok(())
+    }
+
+    /// Sweep [from, to), splitting it between devices, then evaluate rules. Data is max-held into
+    /// the archive `row`, which is started if there is none.
+    fn sweep_devices(&self, from: u32, to: u32, params: SweepParams, engine: &mut Engine, recorders: &[SharedRecorder],
+                     archive: &mut Option<archive::Writer>, row: &mut Option<archive::Row>,
+                     channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) {
+        channel.lock().unwrap().push_back(ScannerStatus::SweepStarted { from, to });
+        if let Err(err) = self.open_archive(archive, params) {
+            channel.lock().unwrap().push_back(ScannerStatus::Error(err));
+        }
+        if let (Some(writer), None) = (archive.as_ref(), row.as_ref()) {
+            *row = Some(writer.header().row(rules::now()));
+        }
+
+        // Every device sweeps its own parts of the range, the ones its tuner can do
+        let (tx, rx) = mpsc::channel();
+        let bands = self.devices.iter().map(|d| d.range.bands.clone()).collect::<Vec<_>>();
+        let parts = sweep::split_range(from, to, &bands);
+        for (d, device) in self.devices.iter().cloned().enumerate() {
+            let own = parts.iter().enumerate().filter(|(_, p)| p.0 == d).map(|(k, p)| (k, p.1, p.2)).collect::<Vec<_>>();
+            if own.is_empty() {
+                continue;
+            }
+            let tx = tx.clone();
+            let scanner = self.clone();
+            let recorder = recorders[d].clone();
+            thread::spawn(move || {
+                for (k, from, to) in own {
+                    debug!("Device {} sweeps {}-{}", device.serial, from, to);
+                    let emit = |status: ScannerStatus| { let _ = tx.send((k, WorkerStatus::Status(status))); };
+                    let res = scanner.sweep(&device, from, to, params, &recorder, &emit);
+                    let _ = tx.send((k, WorkerStatus::Done(res)));
+                }
+            });
+        }
+        drop(tx);
+
+        // Merge into one spectrum ordered by frequency: data of a part is held until all parts
+        // of lower ranges are done
+        let mut pending = (0..parts.len()).map(|_| VecDeque::new()).collect::<Vec<_>>();
+        let mut done = vec![false; parts.len()];
+        let mut current = 0;
+        let mut peaks = vec![None; self.rules.len()];
+        for (k, status) in rx {
+            let mut channel = channel.lock().unwrap();
+            match status {
+                WorkerStatus::Status(status) => {
+                    match &status {
+                        ScannerStatus::Data { freq, bin_width, psd } | ScannerStatus::Refined { freq, bin_width, psd } => {
+                            for (peak, rule) in peaks.iter_mut().zip(&self.rules) {
+                                if let Some(p) = rules::peak(*freq, *bin_width, psd, rule.from, rule.to) {
+                                    *peak = Some(peak.map_or(p, |peak: f64| peak.max(p)));
+                                }
+                            }
+                        },
+                        _ => {},
+                    }
+                    if let (ScannerStatus::Data { freq, bin_width, psd }, Some(writer), Some(row)) = (&status, archive.as_ref(), row.as_mut()) {
+                        writer.header().place(row, *freq, *bin_width, psd);
+                    }
+                    let is_data = match status { ScannerStatus::Data { .. } => true, _ => false };
+                    if is_data && k != current {
+                        pending[k].push_back(status);
+                    } else {
+                        channel.push_back(status);
+                    }
+                },
+                WorkerStatus::Done(res) => {
+                    if let Err(err) = res {
+                        error!("Device {} failed: {}", self.devices[parts[k].0].serial, err);
+                        channel.push_back(ScannerStatus::Error(err));
+                    }
+                    done[k] = true;
+                }
+            }
+            while current < done.len() && done[current] {
+                current += 1;
+                if current < pending.len() {
+                    channel.extend(pending[current].drain(..));
+                }
+            }
+        }
+
+        for (i, transition) in engine.evaluate(&self.rules, &peaks) {
+            self.alert(i, transition == Transition::Triggered, peaks[i].unwrap_or(std::f64::NEG_INFINITY), channel);
+        }
+    }
+
+    /// Range of archive rows: the scanned range, or all enabled ranges of the plan
+    fn archive_range(&self) -> (u32, u32) {
+        match &self.plan {
+            Some(plan) => {
+                let enabled = plan.ranges.iter().filter(|r| r.enabled);
+                (enabled.clone().map(|r| r.from).min().unwrap_or(self.from), enabled.map(|r| r.to).max().unwrap_or(self.to))
+            },
+            None => (self.from, self.to),
+        }
+    }
+
+    /// Create the archive on the first sweep, with the acquisition settings of that sweep
+    fn open_archive(&self, archive: &mut Option<archive::Writer>, params: SweepParams) -> Result<(), ScanError> {
+        let settings = match &self.archive {
+            Some(settings) if archive.is_none() => settings,
+            _ => return Ok(()),
+        };
+        let (from, to) = self.archive_range();
+        let mut header = archive::Header::new(from as f64, to as f64, settings.bin_width.max(1.0));
+        header.samplerate = self.samplerate;
+        header.dwell_ms = params.dwell_ms;
+        header.rbw = params.rbw;
+        header.devices = self.devices.iter().map(|d| DeviceCalibration {
+            serial: d.serial.clone(), ppm: d.ppm, gain: d.gain, level_offset: d.level_offset }).collect();
+        let path = format!("{}_{}.{}", settings.prefix, header.start_time as u64, archive::EXTENSION);
+        *archive = Some(archive::Writer::create(&path, header).map_err(io_error(&path))?);
+        Ok(())
+    }
+
+    /// Append a finished sweep, or plan cycle, to the archive
+    fn archive_row(&self, archive: &mut Option<archive::Writer>, row: Option<archive::Row>,
+                   channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) {
+        if let (Some(writer), Some(row)) = (archive.as_mut(), row) {
+            if let Err(err) = writer.push(row) {
+                let err = io_error(writer.path())(err);
+                channel.lock().unwrap().push_back(ScannerStatus::Error(err));
+            }
+        }
+    }
+
+    fn finish_archive(&self, archive: Option<archive::Writer>, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) {
+        if let Some(writer) = archive {
+            let path = writer.path().to_string();
+            let status = match writer.finish() {
+                Ok(()) => ScannerStatus::Info(format!("Archived sweeps to {}", path)),
+                Err(err) => ScannerStatus::Error(io_error(&path)(err)),
+            };
+            channel.lock().unwrap().push_back(status);
+        }
+    }
+
+    /// Run actions of a rule which changed state. Log and highlight are done by GUI.
+    fn alert(&self, index: usize, triggered: bool, power: f64, channel: &Arc<Mutex<VecDeque<ScannerStatus>>>) {
+        let rule = &self.rules[index];
+        let push = |status| channel.lock().unwrap().push_back(status);
+        push(ScannerStatus::Alert { index, name: rule.name.clone(), actions: rule.actions.clone(), triggered, power });
+        if rule.actions.record {
+            let event = Event { time: rules::now(), rule: rule.name.clone(), triggered, from: rule.from, to: rule.to, power };
+            if let Err(err) = rules::record(&self.alert_dir, &event) {
+                push(ScannerStatus::Info(format!("Failed to record event of '{}': {}", rule.name, err)));
+            }
+        }
+        if !triggered {
+            return;
+        }
+        if !rule.actions.command.is_empty() {
+            if let Err(err) = rules::run_command(rule, power) {
+                push(ScannerStatus::Info(format!("Failed to run command of '{}': {}", rule.name, err)));
+            }
+        }
+        if rule.actions.save_iq {
+            match self.capture_iq(rule) {
+                Ok(path) => push(ScannerStatus::Info(format!("Saved IQ of '{}' to {}", rule.name, path))),
+                Err(err) => push(ScannerStatus::Error(err)),
+            }
+        }
+    }
+
+    /// Record raw IQ at the center of rule's range, right after the sweep which triggered it
+    fn capture_iq(&self, rule: &Rule) -> Result<String, ScanError> {
+        let center = ((rule.from as u64 + rule.to as u64) / 2) as u32;
+        let device = self.devices.iter().find(|d| d.range.contains(center)).unwrap_or(&self.devices[0]);
+        let mut driver = self.open(device)?;
+        set_gain(&mut driver, device.gain)?;
+        tune(&mut driver, &device.serial, center)?;
+        driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
+        let samples = ALERT_IQ_MS * self.samplerate / 1000;
+        let buffer = read(&mut driver, &device.serial, center, calculate_aligned_buffer_size(samples))?;
+
+        let name = rule.name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect::<String>();
+        let path = self.alert_dir.join(format!("iq_{}_{}_{}.cu8", name, center, rules::file_stamp(rules::now()))).
+            to_string_lossy().into_owned();
+        File::create(&path).and_then(|mut file| file.write_all(&buffer[..samples * 2])).
+            map_err(|err| ScanError::Io { path: path.clone(), cause: err.to_string() })?;
+        Ok(path)
+    }
+
+    /// Sweep [from, to) with one device
+    fn sweep(&self, device: &ScanDevice, from: u32, to: u32, params: SweepParams, recorder: &SharedRecorder,
+             emit: &dyn Fn(ScannerStatus)) -> Result<(), ScanError> {
+        let mut driver = self.open(device)?;
+        let mut recorder = recorder.lock().unwrap();
+        self.sweep_passes(&mut driver, device, from, to, params, &mut recorder, emit)
+    }
+
+    /// Coarse sweep followed by adaptive revisits
+    fn sweep_passes(&self, driver: &mut RTLSDRDevice, device: &ScanDevice, from: u32, to: u32, params: SweepParams,
+                    recorder: &mut Option<Recorder>, emit: &dyn Fn(ScannerStatus)) -> Result<(), ScanError> {
+        let steps = self.sweep_pass(driver, device, from, to, params, recorder, emit)?;
+
+        if let Some(adaptive) = self.adaptive {
+            let regions = adaptive::hot_regions(&steps, adaptive.threshold_db);
+            debug!("Device {} revisits {:?}", device.serial, regions);
+            let refine = SweepParams { dwell_ms: adaptive.dwell_ms, rbw: Some(adaptive.rbw), gain: params.gain, refined: true };
+            for (from, to) in regions {
+                if self.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                self.sweep_pass(driver, device, from, to, refine, recorder, emit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sweep [from, to) with opened device, returning levels of visited steps
+    fn sweep_pass(&self, driver: &mut RTLSDRDevice, device: &ScanDevice, from: u32, to: u32, params: SweepParams,
+                  recorder: &mut Option<Recorder>, emit: &dyn Fn(ScannerStatus)) -> Result<Vec<StepLevel>, ScanError> {
+
+        let dwell_samples = (params.dwell_ms * self.samplerate) / 1000;
+        let buffer_size = calculate_aligned_buffer_size(dwell_samples);
+        // Dwell is split into frames of 1/rbw which are averaged. Dwell shorter than 1/rbw limits
+        // the resolution.
+        let sample_count = match params.rbw {
+            Some(rbw) => ((self.samplerate as f64 / rbw).round() as usize).max(2).min(dwell_samples),
+            None => dwell_samples,
+        };
+        let frames = dwell_samples / sample_count;
+        debug!("Buffer size {} bytes, {} samples, {} frames of {}", buffer_size, dwell_samples, frames, sample_count);
+
+        let fftPlan = Plan::new(sample_count as usize).ok_or(ScanError::FftPlan(sample_count))?;
+
+        let mut strategy = self.strategy;
+        if strategy == SweepStrategy::E4000OffsetTuning && driver.set_offset_tuning(true).is_err() {
+            strategy = SweepStrategy::Offset((self.samplerate / 4) as u32);
+            emit(ScannerStatus::Info(
+                format!("Tuner does not support offset tuning, falling back to {:?}", strategy)));
+        }
+        let window = strategy.window(self.samplerate, self.bandwidth).map_err(ScanError::Config)?;
+        let bins = window.bins(sample_count, self.samplerate);
+        // Tuner is not involved in direct sampling, so there is nothing to offset
+        let direct_window = SweepStrategy::Centered.window(self.samplerate, self.bandwidth).unwrap();
+        let direct_bins = direct_window.bins(sample_count, self.samplerate);
+        let bin_width = self.samplerate as f64 / sample_count as f64;
+        debug!("Sweep {:?}: step {} Hz, bins {:?}", strategy, window.width(), bins);
 
         {
-            driver.set_sample_rate(self.samplerate as u32).unwrap();
-            driver.set_tuner_bandwidth(self.bandwidth as u32).unwrap();
-            driver.reset_buffer().unwrap();
+            set_gain(driver, params.gain.or(device.gain))?;
+            driver.set_tuner_bandwidth(strategy.tuner_bandwidth(self.samplerate, self.bandwidth) as u32).
+                map_err(driver_error("set_tuner_bandwidth"))?;
+            driver.reset_buffer().map_err(driver_error("reset_buffer"))?;
         }
 
         let input = fftPlan.get_input();
         let output: &[f64] = fftPlan.get_output();
-
-        /*
-        let mut file = match dump_data {true => Some(BufWriter::new(File::create("./data/raw.mat").unwrap())), false => None};
-        if dump_data {
-            //let mut file = BufWriter::new(File::create("raw.mat").unwrap());
-            file.as_mut().unwrap().write_all(b"# Created by rtl-scanner\n");
-            file.as_mut().unwrap().write_all(b"# name: raw_bytes\n");
-            file.as_mut().unwrap().write_all(b"# type: matrix\n");
-            write!(file.as_mut().unwrap(), "# rows: {}\n", ((end - start) as f64 / step as f64).ceil());
-            write!(file.as_mut().unwrap(), "# columns: {}\n", buffer_size );
-        }
-        */
+        let mut corrector = IqCorrector::new(self.correction);
 
         //
         // TODO: think, if it is possible to do frequencies in rational space and not in f64.
@@ -113,59 +1099,114 @@ impl Scanner {
         // TODO: research delay needed to avoid empty buffer at the start after change frequency
         //
 
-        debug!("Scanning from {} to {}", self.from, self.to);
-        let mut freq: usize = start;
+        debug!("Scanning from {} to {}", from, to);
+        let mut freq = from as i64;
         let mut i = 0;
+        let mut direct = false;
+        let mut steps = vec![];
+
+        while freq < to as i64 {
+            if self.stop.load(Ordering::Relaxed) {
+                emit(ScannerStatus::Info(format!("Scanning stopped at {} MHz", freq as f64 / 1e6)));
+                break;
+            }
+
+            let unexcluded = mask::skip_excluded(freq as u32, &self.exclusions) as i64;
+            if unexcluded != freq {
+                debug!("Skipping excluded {}-{}", freq, unexcluded);
+                freq = unexcluded;
+                continue;
+            }
+
+            let use_direct = self.direct_sampling != DirectSampling::Off &&
+                freq - window.low < device.range.min() as i64 &&
+                freq < DIRECT_SAMPLING_MAX as i64;
+            if use_direct != direct {
+                direct = use_direct;
+                let mode = if direct { self.direct_sampling } else { DirectSampling::Off };
+                driver.set_direct_sampling(mode.mode()).map_err(driver_error("set_direct_sampling"))?;
+                // Direct sampling input is real, so there is no I/Q pair to balance
+                corrector.settings.iq_balance = self.correction.iq_balance && !direct;
+                emit(ScannerStatus::Info(format!("Direct sampling: {:?}", mode)));
+            }
 
-        //print!("Estimated lines: {} {}\n", (end - start) as f64/ step as f64, ((end - start) as f64 / step as f64).ceil());
+            // Every step covers [freq, freq + step) while tuner is at freq - window.low
+            let (window, bins) = if direct { (direct_window, &direct_bins) } else { (window, &bins) };
+            let tuned = freq - window.low;
+            if !direct && !device.range.contains(tuned as u32) {
+                // Skip the gap, or the rest of the sweep if it is above the tuner's range
+                match device.range.next_band(tuned as u32) {
+                    Some(band) => {
+                        debug!("Skipping {}-{} outside of tuner range", freq, band);
+                        freq = band as i64 + window.low;
+                        continue;
+                    },
+                    None => {
+                        emit(ScannerStatus::Info(
+                            format!("Stopped at {} MHz, tuner range is {}", freq as f64 / 1e6, device.range)));
+                        break;
+                    }
+                }
+            }
 
-        while freq <= end {
+            let (device_freq, inverted) = if direct { tuner::direct_tuning(tuned as u32) } else { (tuned as u32, false) };
             let buffer: Vec<u8>;
             {
                 //let driver = s.device.as_mut().unwrap();
-                driver.set_center_freq(freq as u32).unwrap();
-                // TODO: add borrowed buffer override to rtlsdr driver
-                buffer = driver.read_sync(buffer_size as usize).unwrap();
+                match tune(driver, &device.serial, device_freq) {
+                    Ok(()) => {},
+                    // Not fatal, skip the step
+                    Err(err @ ScanError::Tune { .. }) => {
+                        emit(ScannerStatus::Error(err));
+                        freq += window.width() as i64;
+                        continue;
+                    },
+                    Err(err) => return Err(err),
+                }
+                corrector.retuned();
+                buffer = read(driver, &device.serial, device_freq, buffer_size)?;
             }
 
-            /*if file.is_some() {
-                let f = file.as_mut().unwrap();
-                for b in &buffer {
-                    write!(f, "{} ", b);
-                }
-                write!(f, "\n");
-            }*/
+            let limit_reached = match recorder {
+                Some(r) => !r.write(device_freq, &buffer[..dwell_samples * 2]).map_err(io_error(r.path()))?,
+                None => false,
+            };
+            if limit_reached {
+                self.finish_recording(recorder.take().unwrap(), emit);
+            }
 
 
-            freq += step;
+            freq += window.width() as i64;
             if i % 10 == 0 {
                 debug!("> {}", freq as f64/1e6);
             }
             i += 1;
 
-            rtl_import(&buffer, buffer.len(), input);
-            fftPlan.execute();
-
-            // http://www.fftw.org/doc/The-1d-Discrete-Fourier-Transform-_0028DFT_0029.html#The-1d-Discrete-Fourier-Transform-_0028DFT_0029
-            // Note also that we use the standard “in-order” output ordering—the k-th output corresponds to the frequency
-            // k/n (or k/T, where T is your total sampling period). For those who like to think in terms of positive and
-            // negative frequencies, this means that the positive frequencies are stored in the first half of the output
-            // and the negative frequencies are stored in backwards order in the second half of the output.
-            // (The frequency -k/n is the same as the frequency (n-k)/n.)
-            //
-            // Or just numpy implementation:
-            // https://github.com/numpy/numpy/blob/v1.12.0/numpy/fft/helper.py#L74
-
-            // TODO: smooth 0th frequency
-            let dft_out_ordered = output[output.len()/2..].iter().chain(output[..output.len()/2].iter()).cloned().tuples();
-            let complex_dft = dft_out_ordered.
-                map(|(re, im)| Complex64::new(re, im)).
-                // TODO: do not collect but keep propagating Iterator into ::psd
-                collect::<Vec<_>>();
-
-            let psd = dsp::psd(&complex_dft);
-
-            let _fft_step = 1.0 / (self.dwell_ms as f32 / 1000.0);
+            let mut average = vec![0_f64; sample_count];
+            for frame in 0..frames {
+                let offset = frame * sample_count * 2;
+                rtl_import(&buffer[offset..], sample_count * 2, input);
+                corrector.process(input);
+                fftPlan.execute();
+                dsp::accumulate_power(&mut average, &dsp::ordered_psd(output));
+            }
+            let mut psd = dsp::average_power(&average, frames);
+            interpolate_center(&mut psd, self.correction.center_bins);
+            if inverted {
+                // Bin 0 is Nyquist frequency, which is its own mirror
+                psd[1..].reverse();
+            }
+
+            // Keep clean part of the spectrum only and do not overshoot the end of the range
+            let first_bin_freq = tuned as f64 + (bins.start as f64 - sample_count as f64 / 2.0) * bin_width;
+            let mut psd = psd[bins.clone()].iter().map(|p| p + device.level_offset).collect::<Vec<_>>();
+            let overshoot = ((first_bin_freq + psd.len() as f64 * bin_width - to as f64) / bin_width).floor();
+            if overshoot > 0.0 {
+                let len = psd.len().saturating_sub(overshoot as usize);
+                psd.truncate(len);
+            }
+            mask::apply(&mut psd, first_bin_freq, bin_width, &self.exclusions, &self.spurs);
+
             // TODO: send data
             /*
             let mut samples = samples.lock().unwrap();
@@ -173,15 +1214,23 @@ impl Scanner {
                 samples.samples.push(c);
             }
             */
-            channel.lock().unwrap().push_back(ScannerStatus::Data(psd));
+            steps.extend(StepLevel::new(first_bin_freq, bin_width, &psd));
+            if let Some(r) = recorder {
+                for (lower, upper, peak) in adaptive::peaks(first_bin_freq, bin_width, &psd, ANNOTATE_THRESHOLD_DB) {
+                    r.annotate(buffer.len() as u64 / 2, lower, upper, format!("peak {:.1} dB", peak));
+                }
+            }
+            if params.refined {
+                emit(ScannerStatus::Refined { freq: first_bin_freq, bin_width, psd });
+            } else {
+                emit(ScannerStatus::Data { freq: first_bin_freq, bin_width, psd });
+            }
         }
 
-        {
-            let mut channel = channel.lock().unwrap();
-            channel.push_back(ScannerStatus::Info("Scanning complete".to_string()));
-            channel.push_back(ScannerStatus::Complete);
+        if direct {
+            driver.set_direct_sampling(DirectSampling::Off.mode()).map_err(driver_error("set_direct_sampling"))?;
         }
-        Ok(())
+        Ok(steps)
     }
 
     fn refresh(&self) {
@@ -198,9 +1247,10 @@ impl Scanner {
     }
 }
 
+/// Bytes to read for `samples`, rounded up to the 512 byte multiple the driver requires. Only the
+/// first `samples` are used, the rest is padding.
 fn calculate_aligned_buffer_size(samples: usize) -> usize {
     // a sample is a complex byte, thus 2 bytes per sample
     let bytes = samples * 2;
-    // TODO: align to ^2 because FFTW works the fastest than
-    return bytes + bytes % 512;
+    (bytes + 511) / 512 * 512
 }
@@ -0,0 +1,183 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use crate::mask::{FreqRange, Spur};
+use crate::rules::Rule;
+use crate::charts::Colormap;
+use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io;
+
+const SETTINGS_DIR: &str = "rtl-scanner";
+const SETTINGS_FILE: &str = "settings.json";
+/// Settings file which failed to load is moved here instead of being overwritten
+const BACKUP_FILE: &str = "settings.json.bak";
+/// Settings are written here first and then renamed over the settings file
+const TEMP_FILE: &str = "settings.json.tmp";
+const ALERTS_DIR: &str = "alerts";
+const LAYOUT_FILE: &str = "imgui.ini";
+
+/// Settings persisted between runs in `<config dir>/rtl-scanner/settings.json`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Keyed by USB serial number
+    pub devices: BTreeMap<String, DeviceSettings>,
+    /// Ranges the scanner does not sweep
+    pub exclusions: Vec<FreqRange>,
+    /// Known spurs masked out of results
+    pub spurs: Vec<Spur>,
+    /// Alert rules evaluated on every sweep
+    pub rules: Vec<Rule>,
+    pub gui: GuiSettings,
+}
+
+/// Choices made in the GUI, restored on the next start
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuiSettings {
+    /// Last scan range, Hz
+    pub scan_from: u32,
+    pub scan_to: u32,
+    pub dwell_ms: usize,
+    /// Serial numbers of devices used for scanning
+    pub selected_devices: Vec<String>,
+    pub colormap: Colormap,
+    /// Waterfall color scale, dB
+    pub waterfall_floor: f32,
+    pub waterfall_ceiling: f32,
+    /// Show live data as difference from the reference trace
+    pub show_difference: bool,
+    /// Rise over the reference which is highlighted, dB
+    pub rise_threshold: f32,
+    /// Scale and height of charts, by chart name
+    pub charts: BTreeMap<String, ChartSettings>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChartSettings {
+    pub auto_scale: bool,
+    /// Manual y scale, dB
+    pub y_min: f32,
+    pub y_max: f32,
+    pub height: f32,
+}
+
+impl Default for GuiSettings {
+    fn default() -> Self {
+        GuiSettings {
+            scan_from: 60e6 as u32,
+            scan_to: 1700e6 as u32,
+            dwell_ms: crate::DWELL_MS,
+            selected_devices: vec![],
+            colormap: Colormap::Viridis,
+            waterfall_floor: -100.0,
+            waterfall_ceiling: -20.0,
+            show_difference: false,
+            rise_threshold: 10.0,
+            charts: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceSettings {
+    /// Frequency correction, parts per million
+    pub ppm: i32,
+    /// Tuner gain, 10th of dB. None for automatic gain.
+    pub gain: Option<i32>,
+    /// Level calibration added to measured power, dB
+    pub level_offset: f64,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(SETTINGS_DIR).join(SETTINGS_FILE))
+    }
+
+    /// Directory of alert events and IQ captures, created if missing
+    pub fn alerts_dir() -> Option<PathBuf> {
+        let dir = dirs::config_dir()?.join(SETTINGS_DIR).join(ALERTS_DIR);
+        fs::create_dir_all(&dir).ok()?;
+        Some(dir)
+    }
+
+    /// ImGui window layout, next to the settings file. Directory is created so ImGui can write it.
+    pub fn layout_path() -> Option<PathBuf> {
+        let dir = dirs::config_dir()?.join(SETTINGS_DIR);
+        fs::create_dir_all(&dir).ok()?;
+        Some(dir.join(LAYOUT_FILE))
+    }
+
+    /// Load settings or return defaults if there is no settings file yet.
+    pub fn load() -> Result<Settings, io::Error> {
+        let path = match Settings::path() {
+            Some(path) => path,
+            None => return Ok(Settings::default()),
+        };
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+        let file = File::open(path)?;
+        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Move the settings file aside, so that saving defaults after a failed load does not lose it
+    pub fn backup() -> Result<PathBuf, io::Error> {
+        let path = Settings::path().
+            ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+        let backup = path.with_file_name(BACKUP_FILE);
+        fs::rename(&path, &backup)?;
+        Ok(backup)
+    }
+
+    /// Write to a temporary file and rename it over the settings file, so that a crash while
+    /// saving leaves either the old or the new settings
+    pub fn save(&self) -> Result<(), io::Error> {
+        let path = Settings::path().
+            ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_file_name(TEMP_FILE);
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer_pretty(&mut file, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    }
+
+    pub fn device(&self, serial: &str) -> DeviceSettings {
+        self.devices.get(serial).cloned().unwrap_or_default()
+    }
+
+    pub fn device_mut(&mut self, serial: &str) -> &mut DeviceSettings {
+        self.devices.entry(serial.to_string()).or_insert_with(DeviceSettings::default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut settings = Settings::default();
+        settings.device_mut("00000001").ppm = -42;
+        let json = serde_json::to_string(&settings).unwrap();
+        let loaded: Settings = serde_json::from_str(&json).unwrap();
+        assert_eq!(settings, loaded);
+        assert_eq!(-42, loaded.device("00000001").ppm);
+        assert_eq!(0, loaded.device("unknown").ppm);
+    }
+
+    #[test]
+    fn missing_fields_are_defaulted() {
+        let loaded: Settings = serde_json::from_str(r#"{"devices": {"1": {}}}"#).unwrap();
+        assert_eq!(0, loaded.device("1").ppm);
+        assert_eq!(GuiSettings::default(), loaded.gui);
+
+        let loaded: Settings = serde_json::from_str(r#"{"gui": {"scan_to": 433000000, "colormap": "turbo"}}"#).unwrap();
+        assert_eq!((60e6 as u32, 433e6 as u32), (loaded.gui.scan_from, loaded.gui.scan_to));
+        assert_eq!(Colormap::Turbo, loaded.gui.colormap);
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+pub const DATA_EXTENSION: &str = "sigmf-data";
+pub const META_EXTENSION: &str = "sigmf-meta";
+const VERSION: &str = "1.0.0";
+
+/// Contents of `.sigmf-meta` file, see https://github.com/gnuradio/SigMF
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    pub global: Global,
+    #[serde(default)]
+    pub captures: Vec<Capture>,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Global {
+    /// "cu8" or "ci8"
+    #[serde(rename = "core:datatype")]
+    pub datatype: String,
+    #[serde(rename = "core:sample_rate")]
+    pub sample_rate: f64,
+    #[serde(rename = "core:version")]
+    pub version: String,
+    #[serde(rename = "core:hw", default, skip_serializing_if = "Option::is_none")]
+    pub hw: Option<String>,
+    #[serde(rename = "core:recorder", default, skip_serializing_if = "Option::is_none")]
+    pub recorder: Option<String>,
+    #[serde(rename = "core:description", default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Segment of samples captured at one frequency
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capture {
+    #[serde(rename = "core:sample_start")]
+    pub sample_start: u64,
+    #[serde(rename = "core:frequency")]
+    pub frequency: f64,
+    #[serde(rename = "core:datetime", default, skip_serializing_if = "Option::is_none")]
+    pub datetime: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    #[serde(rename = "core:sample_start")]
+    pub sample_start: u64,
+    #[serde(rename = "core:sample_count")]
+    pub sample_count: u64,
+    #[serde(rename = "core:freq_lower_edge")]
+    pub freq_lower_edge: f64,
+    #[serde(rename = "core:freq_upper_edge")]
+    pub freq_upper_edge: f64,
+    #[serde(rename = "core:label", default)]
+    pub label: String,
+}
+
+impl Global {
+    pub fn new(datatype: &str, sample_rate: f64) -> Global {
+        Global {
+            datatype: datatype.to_string(),
+            sample_rate,
+            version: VERSION.to_string(),
+            hw: None,
+            recorder: Some("rtl-scanner".to_string()),
+            description: None,
+        }
+    }
+}
+
+impl Meta {
+    /// Append an annotation, extending an open one instead if it is the same signal in the
+    /// adjacent samples. Several signals of one buffer each extend their own annotation.
+    pub fn annotate(&mut self, annotation: Annotation) {
+        let width = (annotation.freq_upper_edge - annotation.freq_lower_edge).max(1.0);
+        let open = self.annotations.iter_mut().rev().find(|a| a.sample_start + a.sample_count == annotation.sample_start &&
+                (a.freq_lower_edge - annotation.freq_lower_edge).abs() < width &&
+                (a.freq_upper_edge - annotation.freq_upper_edge).abs() < width);
+        match open {
+            Some(open) => open.sample_count += annotation.sample_count,
+            None => self.annotations.push(annotation),
+        }
+    }
+
+    /// Range of samples of capture `i`, up to `total` samples in the data file
+    pub fn capture_samples(&self, i: usize, total: u64) -> (u64, u64) {
+        let start = self.captures[i].sample_start.min(total);
+        let end = self.captures.get(i + 1).map(|c| c.sample_start).unwrap_or(total).min(total);
+        (start, end.max(start))
+    }
+}
+
+/// Strip SigMF extension, if any, so that both data and meta file can be given
+pub fn base_path(path: &str) -> &str {
+    for ext in &[DATA_EXTENSION, META_EXTENSION] {
+        if path.ends_with(ext) && path.len() > ext.len() {
+            return &path[..path.len() - ext.len() - 1];
+        }
+    }
+    path
+}
+
+pub fn write_meta(base: &str, meta: &Meta) -> Result<(), io::Error> {
+    let file = File::create(format!("{}.{}", base, META_EXTENSION))?;
+    serde_json::to_writer_pretty(file, meta).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Recording opened for replay. Samples are read in chunks and converted to cu8, which is what the
+/// scanner works with.
+pub struct Reader {
+    pub meta: Meta,
+    data: BufReader<File>,
+    /// Samples in the data file
+    pub total: u64,
+    /// Sample the data file is at
+    position: u64,
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Reader, io::Error> {
+        let base = base_path(path);
+        let file = File::open(format!("{}.{}", base, META_EXTENSION))?;
+        let meta: Meta = serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match meta.global.datatype.as_str() {
+            "cu8" | "ci8" => {},
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported datatype {}", other))),
+        }
+        let data = File::open(format!("{}.{}", base, DATA_EXTENSION))?;
+        let total = data.metadata()?.len() / 2;
+        Ok(Reader { meta, data: BufReader::new(data), total, position: 0 })
+    }
+
+    /// Read up to `count` samples from sample `start`, fewer at the end of the file
+    pub fn read(&mut self, start: u64, count: usize) -> Result<Vec<u8>, io::Error> {
+        if start != self.position {
+            self.data.seek(SeekFrom::Start(start * 2))?;
+        }
+        let mut buffer = Vec::with_capacity(count * 2);
+        self.data.by_ref().take(count as u64 * 2).read_to_end(&mut buffer)?;
+        self.position = start + buffer.len() as u64 / 2;
+        // Odd trailing byte is not a sample, the next read seeks past it
+        if buffer.len() % 2 == 1 {
+            buffer.pop();
+            self.position = std::u64::MAX;
+        }
+        if self.meta.global.datatype == "ci8" {
+            to_unsigned(&mut buffer);
+        }
+        Ok(buffer)
+    }
+}
+
+/// cu8 <-> ci8, both directions are the same bit flip
+pub fn to_signed(data: &mut [u8]) {
+    for b in data.iter_mut() {
+        *b ^= 0x80;
+    }
+}
+
+pub fn to_unsigned(data: &mut [u8]) {
+    to_signed(data)
+}
+
+/// UTC time in ISO 8601, as required by core:datetime
+pub fn iso8601(secs: f64) -> String {
+    let total = secs.max(0.0) as i64;
+    let (days, rem) = (total / 86400, total % 86400);
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let millis = ((secs - total as f64) * 1000.0) as i64;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotation(start: u64, lower: f64) -> Annotation {
+        Annotation { sample_start: start, sample_count: 100, freq_lower_edge: lower, freq_upper_edge: lower + 10e3, label: String::new() }
+    }
+
+    #[test]
+    fn datetime() {
+        assert_eq!("1970-01-01T00:00:00.000Z", iso8601(0.0));
+        assert_eq!("2019-03-01T12:30:15.500Z", iso8601(1_551_443_415.5));
+    }
+
+    #[test]
+    fn adjacent_annotations_merge() {
+        let mut meta = Meta { global: Global::new("cu8", 2e6), captures: vec![], annotations: vec![] };
+        meta.annotate(annotation(0, 100e6));
+        meta.annotate(annotation(100, 100e6 + 1e3));
+        meta.annotate(annotation(300, 100e6));
+        assert_eq!(2, meta.annotations.len());
+        assert_eq!(200, meta.annotations[0].sample_count);
+
+        // Two signals in every buffer
+        let mut meta = Meta { global: Global::new("cu8", 2e6), captures: vec![], annotations: vec![] };
+        for start in &[0, 100, 200] {
+            meta.annotate(annotation(*start, 100e6));
+            meta.annotate(annotation(*start, 101e6));
+        }
+        assert_eq!(2, meta.annotations.len());
+        assert!(meta.annotations.iter().all(|a| a.sample_count == 300));
+    }
+
+    #[test]
+    fn keys_are_namespaced() {
+        let json = serde_json::to_string(&Global::new("ci8", 2e6)).unwrap();
+        assert!(json.contains("\"core:datatype\":\"ci8\""));
+        assert!(json.contains("\"core:sample_rate\":2000000.0"));
+        assert_eq!("capture", base_path("capture.sigmf-meta"));
+        assert_eq!("capture", base_path("capture.sigmf-data"));
+    }
+}
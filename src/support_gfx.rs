@@ -1,10 +1,14 @@
-use imgui::{FontGlyphRange, ImFontConfig, ImGui, ImVec4, Ui};
+use imgui::{FontGlyphRange, ImFontConfig, ImGui, ImString, ImTexture, ImVec4, Ui};
 use imgui_gfx_renderer::{Renderer, Shaders};
 use imgui_glutin_support;
 use std::time::Instant;
 use crate::gui::State;
 use std::sync::{Arc, Mutex};
 use crate::scanner::ScannerStatus;
+use crate::waterfall::{Waterfall, Update};
+use crate::settings::Settings;
+
+type WaterfallTexture<R> = gfx::handle::Texture<R, gfx::format::R8_G8_B8_A8>;
 
 pub(crate) fn run<F: FnMut(&Ui, &mut Arc<Mutex<State>>) -> bool>(title: String, clear_color: [f32; 4],
                                                                  mut run_ui: F,
@@ -61,7 +65,8 @@ pub(crate) fn run<F: FnMut(&Ui, &mut Arc<Mutex<State>>) -> bool>(title: String,
             style.colors[col] = imgui_gamma_to_linear(style.colors[col]);
         }
     }
-    imgui.set_ini_filename(None);
+    // Window layout is kept next to the settings
+    imgui.set_ini_filename(Settings::layout_path().map(|path| ImString::new(path.to_string_lossy())));
 
     // In the examples we only use integer DPI factors, because the UI can get very blurry
     // otherwise. This might or might not be what you want in a real application.
@@ -79,6 +84,7 @@ pub(crate) fn run<F: FnMut(&Ui, &mut Arc<Mutex<State>>) -> bool>(title: String,
 
     let mut last_frame = Instant::now();
     let mut quit = false;
+    let mut waterfalls = vec![];
 
     loop {
         events_loop.poll_events(|event| {
@@ -123,6 +129,11 @@ pub(crate) fn run<F: FnMut(&Ui, &mut Arc<Mutex<State>>) -> bool>(title: String,
             break;
         }
 
+        {
+            let mut state = state.lock().unwrap();
+            sync_waterfalls(state.waterfalls(), &mut waterfalls, &mut factory, &mut encoder, &mut renderer);
+        }
+
         encoder.clear(&main_color, clear_color);
         renderer
             .render(ui, &mut factory, &mut encoder)
@@ -131,4 +142,64 @@ pub(crate) fn run<F: FnMut(&Ui, &mut Arc<Mutex<State>>) -> bool>(title: String,
         window.context().swap_buffers().unwrap();
         device.cleanup();
     }
-}
\ No newline at end of file
+
+    // Changes made just before closing are not saved yet
+    let mut state = state.lock().unwrap();
+    state.track_gui_settings();
+    if state.settings_changed.is_some() {
+        state.save_settings();
+    }
+}
+
+/// Create textures for new waterfalls, upload changed rows and drop textures of waterfalls which
+/// are gone
+fn sync_waterfalls<R, F, C>(mut current: Vec<&mut Waterfall>, textures: &mut Vec<(ImTexture, WaterfallTexture<R>)>,
+                            factory: &mut F, encoder: &mut gfx::Encoder<R, C>, renderer: &mut Renderer<R>)
+    where R: gfx::Resources, F: gfx::Factory<R>, C: gfx::CommandBuffer<R> {
+    use gfx::format::{ChannelType, R8_G8_B8_A8, Srgba8, Swizzle};
+    use gfx::memory::{Bind, Usage};
+    use gfx::texture::{AaMode, FilterMethod, ImageInfoCommon, Kind, SamplerInfo, WrapMode};
+
+    for waterfall in current.iter_mut().filter(|w| w.texture().is_none()) {
+        let kind = Kind::D2(waterfall.columns as u16, waterfall.rows as u16, AaMode::Single);
+        let texture = factory.create_texture::<R8_G8_B8_A8>(kind, 1, Bind::SHADER_RESOURCE | Bind::TRANSFER_DST,
+                                                            Usage::Data, Some(ChannelType::Srgb)).
+            expect("Failed to create waterfall texture");
+        let view = factory.view_texture_as_shader_resource::<Srgba8>(&texture, (0, 0), Swizzle::new()).
+            expect("Failed to create waterfall texture view");
+        // Rows wrap around vertically, so the ring can be drawn with the newest row on top. Nearest
+        // sampling keeps the oldest row from bleeding into the newest one and the edge columns
+        // from blending with each other.
+        let sampler = factory.create_sampler(SamplerInfo::new(FilterMethod::Scale, WrapMode::Tile));
+        let id = renderer.textures().insert((view, sampler));
+        waterfall.attach(id);
+        textures.push((id, texture));
+    }
+
+    textures.retain(|(id, _)| {
+        let alive = current.iter().any(|w| w.texture() == Some(*id));
+        if !alive {
+            renderer.textures().remove(*id);
+        }
+        alive
+    });
+
+    for waterfall in current.iter_mut() {
+        let texture = match textures.iter().find(|(id, _)| waterfall.texture() == Some(*id)) {
+            Some((_, texture)) => texture,
+            None => continue,
+        };
+        let (columns, rows) = (waterfall.columns as u16, waterfall.rows as u16);
+        let info = |row: u16, height: u16| ImageInfoCommon {
+            xoffset: 0, yoffset: row, zoffset: 0, width: columns, height, depth: 1, format: (), mipmap: 0,
+        };
+        let result = match waterfall.take_update() {
+            Some(Update::All(pixels)) => encoder.update_texture::<R8_G8_B8_A8, Srgba8>(texture, None, info(0, rows), &pixels),
+            Some(Update::Rows(changed)) => changed.iter().
+                map(|(row, pixels)| encoder.update_texture::<R8_G8_B8_A8, Srgba8>(texture, None, info(*row as u16, 1), pixels)).
+                collect(),
+            None => Ok(()),
+        };
+        result.expect("Failed to upload waterfall");
+    }
+}
@@ -0,0 +1,192 @@
+use std::ops::Range;
+
+/// Distance from DC which is considered to be polluted by DC spike, Hz
+pub const DC_GUARD: i64 = 10_000;
+/// Fraction of the sample rate not affected by anti-aliasing filter roll-off
+pub const USABLE_FRACTION: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SweepStrategy {
+    /// Tune to the center of every step. DC spike is in the middle of kept spectrum and is hidden
+    /// by IQ correction and center bins interpolation.
+    Centered,
+    /// Tune below the step by given offset (Hz) and keep only the part of spectrum which is
+    /// clear of DC spike.
+    Offset(u32),
+    /// E4000 hardware offset tuning. Tuner moves DC spike out of the band by itself, so captures
+    /// are handled as `Centered`. Other tuners fall back to `Offset`.
+    E4000OffsetTuning,
+}
+
+/// Part of a capture kept by scanner, in Hz relative to tuned frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub low: i64,
+    pub high: i64,
+}
+
+impl SweepStrategy {
+    pub fn window(&self, samplerate: usize, bandwidth: usize) -> Result<Window, String> {
+        match *self {
+            SweepStrategy::Centered | SweepStrategy::E4000OffsetTuning => {
+                let half = bandwidth.min(samplerate) as i64 / 2;
+                Ok(Window { low: -half, high: half })
+            },
+            SweepStrategy::Offset(offset) => {
+                let offset = offset as i64;
+                let half = bandwidth as i64 / 2;
+                let usable = (samplerate as f64 * USABLE_FRACTION / 2.0) as i64;
+                let low = (offset - half).max(DC_GUARD);
+                let high = (offset + half).min(usable);
+                if high <= low {
+                    return Err(format!("Offset {} Hz leaves no usable spectrum at sample rate {}", offset, samplerate));
+                }
+                Ok(Window { low, high })
+            },
+        }
+    }
+
+    /// Bandwidth the tuner should be configured to so that the kept window is not filtered out.
+    pub fn tuner_bandwidth(&self, samplerate: usize, bandwidth: usize) -> usize {
+        match *self {
+            SweepStrategy::Offset(_) => samplerate,
+            _ => bandwidth,
+        }
+    }
+}
+
+impl Window {
+    pub fn width(&self) -> usize {
+        (self.high - self.low) as usize
+    }
+
+    /// Bins of ordered (DC in the middle) spectrum of `n` bins which fall into the window.
+    pub fn bins(&self, n: usize, samplerate: usize) -> Range<usize> {
+        let bin = |offset: i64| {
+            let k = n as i64 / 2 + (offset as f64 * n as f64 / samplerate as f64).round() as i64;
+            k.max(0).min(n as i64) as usize
+        };
+        bin(self.low)..bin(self.high)
+    }
+}
+
+/// Split [from, to) between devices tuning to `bands` (one list per device), so that no device
+/// gets a part it can't tune to while another one can. Parts nobody can tune to are shared by all.
+/// Widths are balanced between devices. Returns (device, from, to) ordered by frequency, a device
+/// may get several parts.
+pub fn split_range(from: u32, to: u32, bands: &[Vec<(u32, u32)>]) -> Vec<(usize, u32, u32)> {
+    if to <= from || bands.is_empty() {
+        return vec![];
+    }
+    let (from, to) = (from as u64, to as u64);
+    let mut edges = vec![from, to];
+    edges.extend(bands.iter().flatten().flat_map(|&(a, b)| vec![a as u64, b as u64]).filter(|&e| e > from && e < to));
+    edges.sort();
+    edges.dedup();
+    let intervals = edges.windows(2).map(|w| {
+        let covering = (0..bands.len()).
+            filter(|&d| bands[d].iter().any(|&(a, b)| a as u64 <= w[0] && w[1] <= b as u64)).
+            collect::<Vec<_>>();
+        (w[0], w[1], if covering.is_empty() { (0..bands.len()).collect() } else { covering })
+    }).collect::<Vec<_>>();
+
+    // Parts only one device can do are counted first, so that shared ones even out the load
+    let mut load = vec![0; bands.len()];
+    for (a, b, devices) in intervals.iter().filter(|i| i.2.len() == 1) {
+        load[devices[0]] += b - a;
+    }
+    let mut parts: Vec<(usize, u64, u64)> = vec![];
+    for (a, b, devices) in &intervals {
+        let shares = if devices.len() == 1 {
+            vec![b - a]
+        } else {
+            let shares = fill(&devices.iter().map(|&d| load[d]).collect::<Vec<_>>(), b - a);
+            for (&d, share) in devices.iter().zip(&shares) {
+                load[d] += share;
+            }
+            shares
+        };
+        let mut start = *a;
+        for (&d, share) in devices.iter().zip(shares).filter(|(_, share)| *share > 0) {
+            let end = start + share;
+            match parts.last_mut() {
+                Some(last) if last.0 == d && last.2 == start => last.2 = end,
+                _ => parts.push((d, start, end)),
+            }
+            start = end;
+        }
+    }
+    parts.into_iter().map(|(d, a, b)| (d, a as u32, b as u32)).collect()
+}
+
+/// Shares of `width` which bring `loads` as close to each other as possible
+fn fill(loads: &[u64], width: u64) -> Vec<u64> {
+    let mut sorted = loads.to_vec();
+    sorted.sort();
+    // Level to which the lowest loads are raised
+    let mut level = 0;
+    for k in 1..=sorted.len() {
+        level = (width + sorted[..k].iter().sum::<u64>()) / k as u64;
+        if k == sorted.len() || level <= sorted[k] {
+            break;
+        }
+    }
+    let mut shares = loads.iter().map(|&l| level.saturating_sub(l)).collect::<Vec<_>>();
+    // Rounding leftover
+    let rest = width - shares.iter().sum::<u64>();
+    if let Some(i) = shares.iter().position(|&s| s > 0).or(Some(0)) {
+        shares[i] += rest;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn centered_window() {
+        let window = SweepStrategy::Centered.window(2_000_000, 1_000_000).unwrap();
+        assert_eq!(Window { low: -500_000, high: 500_000 }, window);
+        assert_eq!(1_000_000, window.width());
+        assert_eq!(250..750, window.bins(1000, 2_000_000));
+    }
+
+    #[test]
+    fn offset_window_avoids_dc() {
+        let window = SweepStrategy::Offset(500_000).window(2_000_000, 1_000_000).unwrap();
+        assert_eq!(Window { low: DC_GUARD, high: 900_000 }, window);
+        let bins = window.bins(1000, 2_000_000);
+        assert!(bins.start > 500);
+        assert!(bins.end <= 1000);
+    }
+
+    #[test]
+    fn split_is_contiguous() {
+        let wide = vec![(0, 1000)];
+        let parts = split_range(100, 200, &[wide.clone(), wide.clone(), wide.clone()]);
+        assert_eq!(vec![(0, 100, 134), (1, 134, 167), (2, 167, 200)], parts);
+        assert_eq!(vec![(0, 100, 200)], split_range(100, 200, &[wide]));
+    }
+
+    #[test]
+    fn split_follows_tuner_bands() {
+        // E4000-like gap at 110-125 and a device covering only 100-150
+        let e4000 = vec![(50, 110), (125, 220)];
+        let narrow = vec![(100, 150)];
+        let parts = split_range(60, 200, &[e4000.clone(), narrow]);
+        assert_eq!(vec![(0, 60, 100), (1, 100, 150), (0, 150, 200)], parts);
+
+        // Nobody covers the gap, it goes to both
+        let parts = split_range(100, 130, &[e4000.clone(), e4000]);
+        assert_eq!((100, 130), (parts[0].1, parts.last().unwrap().2));
+        assert!(parts.windows(2).all(|w| w[0].2 == w[1].1));
+        let width = |d| parts.iter().filter(|p| p.0 == d).map(|p| p.2 - p.1).sum::<u32>();
+        assert_eq!((15, 15), (width(0), width(1)));
+    }
+
+    #[test]
+    fn offset_out_of_band() {
+        assert!(SweepStrategy::Offset(2_000_000).window(2_000_000, 1_000_000).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+
+/// RTL2832 crystal frequency. In direct sampling mode ADC samples at this rate.
+const XTAL: u32 = 28_800_000;
+/// Lowest frequency usable in direct sampling mode, below it ADC input coupling cuts the signal.
+pub const DIRECT_SAMPLING_MIN: u32 = 500_000;
+/// Highest frequency usable in direct sampling mode, second Nyquist zone ends at the xtal frequency.
+pub const DIRECT_SAMPLING_MAX: u32 = XTAL;
+
+/// Which ADC branch HF antenna is connected to. Depends on hardware, most HF-modified dongles
+/// use Q branch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirectSampling {
+    Off,
+    IBranch,
+    QBranch,
+}
+
+impl DirectSampling {
+    /// Mode as understood by `rtlsdr_set_direct_sampling`
+    pub fn mode(&self) -> i32 {
+        match *self {
+            DirectSampling::Off => 0,
+            DirectSampling::IBranch => 1,
+            DirectSampling::QBranch => 2,
+        }
+    }
+}
+
+/// Frequency bands tuner can be tuned to, sorted and not overlapping, Hz.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunerRange {
+    pub bands: Vec<(u32, u32)>,
+}
+
+impl TunerRange {
+    pub fn detect(tuner_type: &str) -> TunerRange {
+        let bands = if tuner_type.contains("E4000") {
+            // PLL does not lock in L-band gap
+            vec![(52_000_000, 1_100_000_000), (1_250_000_000, 2_200_000_000)]
+        } else if tuner_type.contains("FC0012") {
+            vec![(22_000_000, 948_600_000)]
+        } else if tuner_type.contains("FC0013") {
+            vec![(22_000_000, 1_100_000_000)]
+        } else if tuner_type.contains("FC2580") {
+            vec![(146_000_000, 308_000_000), (438_000_000, 924_000_000)]
+        } else {
+            // R820T, R828D and unknown
+            vec![(24_000_000, 1_766_000_000)]
+        };
+        TunerRange { bands }
+    }
+
+    pub fn min(&self) -> u32 {
+        self.bands.first().map(|b| b.0).unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u32 {
+        self.bands.last().map(|b| b.1).unwrap_or(0)
+    }
+
+    pub fn contains(&self, freq: u32) -> bool {
+        self.bands.iter().any(|&(from, to)| from <= freq && freq <= to)
+    }
+
+    /// Start of the first band above `freq`, if `freq` is in a gap or below the range.
+    pub fn next_band(&self, freq: u32) -> Option<u32> {
+        self.bands.iter().map(|b| b.0).find(|&from| from > freq)
+    }
+}
+
+impl fmt::Display for TunerRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bands = self.bands.iter().
+            map(|&(from, to)| format!("{}-{}", from as f64 / 1e6, to as f64 / 1e6)).
+            collect::<Vec<_>>();
+        write!(f, "{} MHz", bands.join(", "))
+    }
+}
+
+/// Frequency to program into RTL2832 DDC in direct sampling mode to receive `freq`, and whether
+/// resulting spectrum is inverted.
+///
+/// Input is real-valued, so everything in the second Nyquist zone (xtal/2..xtal) is a mirror
+/// image of the first one. We always tune within the first zone and flip the spectrum back for
+/// the second zone.
+pub fn direct_tuning(freq: u32) -> (u32, bool) {
+    if freq <= XTAL / 2 {
+        (freq, false)
+    } else {
+        (XTAL - freq.min(XTAL), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_nyquist_zone() {
+        assert_eq!((7_000_000, false), direct_tuning(7_000_000));
+        assert_eq!((XTAL / 2, false), direct_tuning(XTAL / 2));
+    }
+
+    #[test]
+    fn second_nyquist_zone_is_inverted() {
+        assert_eq!((7_800_000, true), direct_tuning(21_000_000));
+    }
+
+    #[test]
+    fn tuner_limits() {
+        assert_eq!(24_000_000, TunerRange::detect("Rafael Micro R820T").min());
+        assert_eq!(52_000_000, TunerRange::detect("Elonics E4000").min());
+        assert_eq!(2_200_000_000, TunerRange::detect("Elonics E4000").max());
+    }
+
+    #[test]
+    fn e4000_gap() {
+        let range = TunerRange::detect("Elonics E4000");
+        assert!(range.contains(1_000_000_000));
+        assert!(!range.contains(1_200_000_000));
+        assert_eq!(Some(1_250_000_000), range.next_band(1_200_000_000));
+        assert_eq!(Some(52_000_000), range.next_band(10_000_000));
+        assert_eq!(None, range.next_band(2_100_000_000));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("146-308, 438-924 MHz", TunerRange::detect("FCI FC2580").to_string());
+    }
+}
@@ -0,0 +1,142 @@
+use imgui::ImTexture;
+use crate::charts::Colormap;
+use crate::decimate;
+
+/// Scrolling waterfall image. Rows are kept decimated to `columns` levels in a ring, colored on
+/// upload and drawn from a texture which `support_gfx` keeps in sync.
+pub struct Waterfall {
+    pub columns: usize,
+    pub rows: usize,
+    /// dB, `rows` x `columns`, -inf where there is no data
+    levels: Vec<f32>,
+    /// Ring row of the newest spectrum
+    head: usize,
+    colormap: Colormap,
+    floor: f32,
+    ceiling: f32,
+    /// Ring rows changed since the last upload
+    changed: Vec<usize>,
+    /// Whole image has to be uploaded
+    invalid: bool,
+    /// Set by renderer once the texture exists
+    texture: Option<ImTexture>,
+}
+
+pub enum Update {
+    /// Ring row and its pixels
+    Rows(Vec<(usize, Vec<[u8; 4]>)>),
+    /// Pixels of all rows
+    All(Vec<[u8; 4]>),
+}
+
+impl Waterfall {
+    pub fn new(columns: usize, rows: usize) -> Waterfall {
+        Waterfall {
+            columns,
+            rows,
+            levels: vec![std::f32::NEG_INFINITY; columns * rows],
+            head: 0,
+            colormap: Colormap::Viridis,
+            floor: -100.0,
+            ceiling: -20.0,
+            changed: vec![],
+            invalid: true,
+            texture: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for level in &mut self.levels {
+            *level = std::f32::NEG_INFINITY;
+        }
+        self.invalid = true;
+    }
+
+    /// Add the newest spectrum on top
+    pub fn push(&mut self, spectrum: &[f32]) {
+        self.head = (self.head + self.rows - 1) % self.rows;
+        let row = &mut self.levels[self.head * self.columns..(self.head + 1) * self.columns];
+        for (level, envelope) in row.iter_mut().zip(decimate::whole(spectrum, self.columns)) {
+            *level = envelope.max;
+        }
+        if self.changed.len() < self.rows {
+            self.changed.push(self.head);
+        } else {
+            self.invalid = true;
+        }
+    }
+
+    /// Recolor the image if the scale changed
+    pub fn set_scale(&mut self, colormap: Colormap, floor: f32, ceiling: f32) {
+        if (colormap, floor, ceiling) != (self.colormap, self.floor, self.ceiling) {
+            self.colormap = colormap;
+            self.floor = floor;
+            self.ceiling = ceiling;
+            self.invalid = true;
+        }
+    }
+
+    pub fn texture(&self) -> Option<ImTexture> {
+        self.texture
+    }
+
+    /// Renderer created a texture for the waterfall, it needs all pixels
+    pub fn attach(&mut self, texture: ImTexture) {
+        self.texture = Some(texture);
+        self.invalid = true;
+    }
+
+    /// Vertical texture coordinates showing the newest row on top. Texture wraps around.
+    pub fn v_range(&self) -> (f32, f32) {
+        let top = self.head as f32 / self.rows as f32;
+        (top, top + 1.0)
+    }
+
+    /// Pixels to upload, None if texture is up to date
+    pub fn take_update(&mut self) -> Option<Update> {
+        if self.invalid {
+            self.invalid = false;
+            self.changed.clear();
+            return Some(Update::All(self.levels.iter().map(|&l| self.colormap.color(l, self.floor, self.ceiling)).collect()));
+        }
+        if self.changed.is_empty() {
+            return None;
+        }
+        let rows = self.changed.drain(..).collect::<Vec<_>>();
+        Some(Update::Rows(rows.into_iter().map(|row| (row, self.row_pixels(row))).collect()))
+    }
+
+    fn row_pixels(&self, row: usize) -> Vec<[u8; 4]> {
+        self.levels[row * self.columns..(row + 1) * self.columns].iter().
+            map(|&l| self.colormap.color(l, self.floor, self.ceiling)).
+            collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_scroll_down() {
+        let mut waterfall = Waterfall::new(2, 3);
+        assert!(match waterfall.take_update() { Some(Update::All(pixels)) => pixels.len() == 6, _ => false });
+        assert!(waterfall.take_update().is_none());
+
+        waterfall.set_scale(Colormap::Grayscale, -100.0, 0.0);
+        waterfall.take_update();
+        waterfall.push(&[-100.0, -100.0, 0.0, 0.0]);
+        waterfall.push(&[0.0, -50.0]);
+        // Newest row is on top, the first one below it
+        assert_eq!(1, waterfall.head);
+        assert_eq!((1.0 / 3.0, 1.0 + 1.0 / 3.0), waterfall.v_range());
+        match waterfall.take_update() {
+            Some(Update::Rows(rows)) => {
+                assert_eq!(vec![2, 1], rows.iter().map(|r| r.0).collect::<Vec<_>>());
+                assert_eq!(vec![[0, 0, 0, 255], [255, 255, 255, 255]], rows[0].1);
+                assert_eq!(vec![[255, 255, 255, 255], [128, 128, 128, 255]], rows[1].1);
+            },
+            _ => panic!("expected rows"),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::f64::consts::PI;
+use std::ops::Range;
+use crate::sweep::{DC_GUARD, USABLE_FRACTION};
+
+/// Taps of decimation filter per unit of decimation factor
+const TAPS_PER_FACTOR: usize = 8;
+/// Sample rate after decimation relative to the span, leaves room for filter roll-off
+const SPAN_MARGIN: f64 = 1.25;
+
+/// High resolution view of a span narrow enough for a single tuning
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoomSettings {
+    /// Hz
+    pub from: u32,
+    /// Hz
+    pub to: u32,
+    pub fft_size: usize,
+    /// FFT frames averaged into the result
+    pub averages: usize,
+}
+
+/// How the span is acquired
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoomPlan {
+    /// Frequency the tuner is set to, Hz
+    pub tuned: u32,
+    /// Center of the span relative to `tuned`, Hz
+    pub shift: f64,
+    pub decimation: usize,
+}
+
+impl ZoomPlan {
+    pub fn new(from: u32, to: u32, samplerate: usize) -> Result<ZoomPlan, String> {
+        if from >= to {
+            return Err(format!("Invalid zoom span {}-{}", from, to));
+        }
+        let span = (to - from) as f64;
+        let usable = samplerate as f64 * USABLE_FRACTION;
+        if span > usable {
+            return Err(format!("Zoom span {} kHz does not fit into {} kHz of a single tuning",
+                               span / 1e3, usable / 1e3));
+        }
+        // Keep the span clear of DC spike if there is room for it
+        let shift = if span + DC_GUARD as f64 <= usable / 2.0 { DC_GUARD as f64 + span / 2.0 } else { 0.0 };
+        let center = (from as f64 + to as f64) / 2.0;
+        let decimation = ((samplerate as f64 / (span * SPAN_MARGIN)).floor() as usize).max(1);
+        Ok(ZoomPlan { tuned: (center - shift).round() as u32, shift, decimation })
+    }
+
+    /// Raw samples needed for `samples` decimated ones
+    pub fn raw_samples(&self, samples: usize) -> usize {
+        samples * self.decimation + taps(self.decimation)
+    }
+
+    /// Bins of ordered spectrum of `fft_size` decimated samples which fall into [from, to), with
+    /// frequency of the first bin and bin width.
+    pub fn bins(&self, from: u32, to: u32, fft_size: usize, samplerate: usize) -> (Range<usize>, f64, f64) {
+        let bin_width = samplerate as f64 / self.decimation as f64 / fft_size as f64;
+        let center = self.tuned as f64 + self.shift;
+        let bin = |freq: f64| {
+            let k = (fft_size / 2) as f64 + ((freq - center) / bin_width).round();
+            k.max(0.0).min(fft_size as f64) as usize
+        };
+        let bins = bin(from as f64)..bin(to as f64);
+        let first = center + (bins.start as f64 - (fft_size / 2) as f64) * bin_width;
+        (bins, first, bin_width)
+    }
+}
+
+fn taps(decimation: usize) -> usize {
+    TAPS_PER_FACTOR * decimation + 1
+}
+
+/// Windowed sinc low-pass filter with cut-off at the Nyquist frequency of decimated signal
+fn lowpass(decimation: usize) -> Vec<f64> {
+    let n = taps(decimation);
+    let m = (n - 1) as f64 / 2.0;
+    let cutoff = 0.5 / decimation as f64;
+    let mut h = (0..n).map(|k| {
+        let x = k as f64 - m;
+        let sinc = if x == 0.0 { 2.0 * cutoff } else { (2.0 * PI * cutoff * x).sin() / (PI * x) };
+        let hamming = 0.54 - 0.46 * (2.0 * PI * k as f64 / (n - 1).max(1) as f64).cos();
+        sinc * hamming
+    }).collect::<Vec<_>>();
+    let sum: f64 = h.iter().sum();
+    for t in h.iter_mut() {
+        *t /= sum;
+    }
+    h
+}
+
+/// Move `shift` Hz of interleaved complex `iq` to DC, filter and keep every `decimation`-th
+/// sample. Returns interleaved complex samples.
+pub fn decimate(iq: &[f64], shift: f64, samplerate: usize, decimation: usize) -> Vec<f64> {
+    let len = iq.len() / 2;
+    let step = -2.0 * PI * shift / samplerate as f64;
+    let mixed = (0..len).map(|n| {
+        let (sin, cos) = (step * n as f64).sin_cos();
+        let (i, q) = (iq[2 * n], iq[2 * n + 1]);
+        (i * cos - q * sin, i * sin + q * cos)
+    }).collect::<Vec<_>>();
+    if decimation <= 1 {
+        return mixed.into_iter().flat_map(|(i, q)| vec![i, q]).collect();
+    }
+
+    let h = lowpass(decimation);
+    let mut out = Vec::with_capacity(len / decimation * 2 + 2);
+    let mut start = 0;
+    while start + h.len() <= len {
+        let (mut i, mut q) = (0.0, 0.0);
+        for (t, &(si, sq)) in h.iter().zip(&mixed[start..start + h.len()]) {
+            i += t * si;
+            q += t * sq;
+        }
+        out.push(i);
+        out.push(q);
+        start += decimation;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f64, samplerate: usize, len: usize) -> Vec<f64> {
+        (0..len).flat_map(|n| {
+            let phase = 2.0 * PI * freq * n as f64 / samplerate as f64;
+            vec![phase.cos(), phase.sin()]
+        }).collect()
+    }
+
+    fn power(iq: &[f64]) -> f64 {
+        iq.chunks(2).map(|c| c[0] * c[0] + c[1] * c[1]).sum::<f64>() / (iq.len() / 2) as f64
+    }
+
+    #[test]
+    fn narrow_span_avoids_dc() {
+        let plan = ZoomPlan::new(100_000_000, 100_100_000, 2_000_000).unwrap();
+        assert_eq!(16, plan.decimation);
+        assert!(plan.shift - 50_000.0 >= DC_GUARD as f64);
+        let (bins, first, bin_width) = plan.bins(100_000_000, 100_100_000, 1024, 2_000_000);
+        assert!((bin_width - 2e6 / 16.0 / 1024.0).abs() < 1e-9);
+        assert!((first - 100e6).abs() <= bin_width);
+        assert!(((bins.end - bins.start) as f64 * bin_width - 100e3).abs() <= bin_width);
+    }
+
+    #[test]
+    fn wide_span_is_rejected() {
+        assert!(ZoomPlan::new(100_000_000, 102_000_000, 2_000_000).is_err());
+        // Wide but fitting span is centered
+        assert_eq!(0.0, ZoomPlan::new(100_000_000, 101_500_000, 2_000_000).unwrap().shift);
+    }
+
+    #[test]
+    fn decimation_keeps_span_and_rejects_rest() {
+        let samplerate = 2_000_000;
+        let shift = 300_000.0;
+        let kept = decimate(&tone(shift + 1_000.0, samplerate, 20_000), shift, samplerate, 16);
+        let rejected = decimate(&tone(shift + 400_000.0, samplerate, 20_000), shift, samplerate, 16);
+        assert!(kept.len() / 2 >= 20_000 / 16 - 10);
+        assert!((power(&kept) - 1.0).abs() < 0.05);
+        assert!(10.0 * power(&rejected).log10() < -40.0);
+    }
+}